@@ -0,0 +1,37 @@
+use crate::name::{LeggiNomi, NamePtr};
+use crate::util::Environment;
+
+// assiomi è il risultato di Environment::assiomi
+fn nomi_assiomi(env: &Environment, assiomi: &[usize]) -> String {
+    assiomi.iter()
+        .map(|&a| env.display_name(env.declars[a].name()).to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// come #print axioms di Lean, per ogni dichiarazione in indici
+pub fn stampa_assiomi(env: &Environment, indici: &[usize], assiomi: &[Vec<usize>]) {
+    println!("\nAssiomi usati:");
+    for &i in indici {
+        let nome = env.display_name(env.declars[i].name());
+        match assiomi[i].as_slice() {
+            [] => println!("  {} non dipende da nessun assioma", nome),
+            usati => println!("  {} dipende da: {}", nome, nomi_assiomi(env, usati)),
+        }
+    }
+}
+
+// le dichiarazioni in indici che usano assiomi diversi da consentiti, con quegli assiomi
+pub fn assiomi_non_consentiti<'a>(env: &Environment<'a>, indici: &[usize], assiomi: &[Vec<usize>], consentiti: &[NamePtr<'a>]) -> Vec<(usize, Vec<usize>)> {
+    indici.iter()
+        .map(|&i| (i, assiomi[i].iter().copied().filter(|&a| !consentiti.contains(&env.declars[a].name())).collect::<Vec<_>>()))
+        // un assioma non consentito può comunque essere dichiarato
+        .filter(|(i, extra)| !extra.is_empty() && extra != &[*i])
+        .collect()
+}
+
+pub fn stampa_non_consentiti(env: &Environment, non_consentiti: &[(usize, Vec<usize>)]) {
+    for (i, extra) in non_consentiti {
+        println!("La dichiarazione {} usa assiomi non consentiti: {}", env.display_name(env.declars[*i].name()), nomi_assiomi(env, extra));
+    }
+}
@@ -2,7 +2,7 @@ use std::hash::{ Hash, Hasher };
 use crate::expr::ExprPtr;
 use crate::name::NamePtr;
 use crate::universe::UparamsPtr;
-use crate::util::{Ptr, ExportFile};
+use crate::util::{Ptr, TypeChecker};
 use crate::hash64;
 use Declar::*;
 
@@ -22,7 +22,7 @@ impl<'a> Hash for RecRule<'a> {
 
 pub type RecRulePtr<'a> = Ptr<&'a RecRule<'a>>;
 
-impl<'t> ExportFile<'t> {
+impl<'e, 't> TypeChecker<'e, 't> {
     pub fn rec_rule(&mut self, ctor_name: NamePtr<'t>, num_param: u32, val : ExprPtr<'t>) -> RecRulePtr<'t> {
         let hash = hash64!(REC_RULE_HASH, ctor_name, num_param, val);
         self.alloc_rec_rule( RecRule { ctor_name, num_param, val , hash})
@@ -64,5 +64,18 @@ impl<'a> Declar<'a> {
             _ => None,
         }
     }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Axiom { .. } => "axiom",
+            Quot { .. } => "quot",
+            Opaq { .. } => "opaque",
+            Theorem { .. } => "theorem",
+            Definition { .. } => "definition",
+            Inductive { .. } => "inductive",
+            Constructor { .. } => "constructor",
+            Recursor { .. } => "recursor",
+        }
+    }
 }
 
@@ -0,0 +1,111 @@
+use crate::expr::ExprPtr;
+use crate::name::NamePtr;
+use crate::universe::{FallimentoUniverso, UniversePtr};
+use crate::util::TypeChecker;
+use TcError::*;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcError<'a> {
+    UndefinedConstant { declar: NamePtr<'a>, costante: NamePtr<'a> },
+    ForwardReference { declar: NamePtr<'a>, costante: NamePtr<'a> },
+    SelfReference { declar: NamePtr<'a> },
+    // la Const costante ha dati universi ma la sua dichiarazione ne ha attesi
+    UniverseArityMismatch { declar: NamePtr<'a>, costante: NamePtr<'a>, attesi: usize, dati: usize },
+    LooseBoundVar { declar: NamePtr<'a>, in_val: bool, range: u32 },
+    TypeMismatch { declar: NamePtr<'a>, ty: ExprPtr<'a>, val_ty: ExprPtr<'a>, universi: Option<FallimentoUniverso<'a>> },
+    LetTypeMismatch { declar: NamePtr<'a>, ty: ExprPtr<'a>, val_ty: ExprPtr<'a>, universi: Option<FallimentoUniverso<'a>> },
+    AppTypeMismatch { declar: NamePtr<'a>, param_ty: ExprPtr<'a>, arg_ty: ExprPtr<'a>, universi: Option<FallimentoUniverso<'a>> },
+    // il tipo di qualcosa applicato a un argomento non è un Pi
+    FunctionExpected { declar: NamePtr<'a>, fun_ty: ExprPtr<'a> },
+    // e viene usato come tipo, ma il suo tipo è ty
+    SortExpected { declar: NamePtr<'a>, e: ExprPtr<'a>, ty: ExprPtr<'a> },
+    BadProjection { declar: NamePtr<'a>, proj: ExprPtr<'a>, motivo: &'static str },
+    UndeclaredUniverseParam { declar: NamePtr<'a>, universe: UniversePtr<'a> },
+    // tempo indica se è scaduto il tempo invece dei passi
+    DeclarationTimedOut { declar: NamePtr<'a>, passi: u64, tempo: bool },
+    // ricorsione indica se è troppo annidato il controllo invece del termine
+    DeclarationTooDeep { declar: NamePtr<'a>, profondita: u32, ricorsione: bool },
+}
+
+impl<'a> TcError<'a> {
+    pub fn declar(&self) -> NamePtr<'a> {
+        match self {
+            UndefinedConstant { declar, .. } | ForwardReference { declar, .. }
+            | SelfReference { declar } | UniverseArityMismatch { declar, .. } | LooseBoundVar { declar, .. } | TypeMismatch { declar, .. }
+            | LetTypeMismatch { declar, .. } | AppTypeMismatch { declar, .. } | FunctionExpected { declar, .. } | SortExpected { declar, .. }
+            | BadProjection { declar, .. } | UndeclaredUniverseParam { declar, .. }
+            | DeclarationTimedOut { declar, .. } | DeclarationTooDeep { declar, .. } => *declar,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UndefinedConstant { .. } => "UndefinedConstant",
+            ForwardReference { .. } => "ForwardReference",
+            SelfReference { .. } => "SelfReference",
+            UniverseArityMismatch { .. } => "UniverseArityMismatch",
+            LooseBoundVar { .. } => "LooseBoundVar",
+            TypeMismatch { .. } => "TypeMismatch",
+            LetTypeMismatch { .. } => "LetTypeMismatch",
+            AppTypeMismatch { .. } => "AppTypeMismatch",
+            FunctionExpected { .. } => "FunctionExpected",
+            SortExpected { .. } => "SortExpected",
+            BadProjection { .. } => "BadProjection",
+            UndeclaredUniverseParam { .. } => "UndeclaredUniverseParam",
+            DeclarationTimedOut { .. } => "DeclarationTimedOut",
+            DeclarationTooDeep { .. } => "DeclarationTooDeep",
+        }
+    }
+}
+
+impl<'e, 't> TypeChecker<'e, 't> {
+    pub fn fmt_error(&self, e: &TcError<'t>) -> String {
+        match e {
+            UndefinedConstant { declar, costante } =>
+                format!("Errore nella dichiarazione {}: costante {} non definita", self.fmt_name(*declar), self.fmt_name(*costante)),
+            ForwardReference { declar, costante } =>
+                format!("Errore nella dichiarazione {}: costante {} usata prima di essere dichiarata", self.fmt_name(*declar), self.fmt_name(*costante)),
+            SelfReference { declar } =>
+                format!("Errore nella dichiarazione {}: la dichiarazione fa riferimento a sé stessa", self.fmt_name(*declar)),
+            UniverseArityMismatch { declar, costante, attesi, dati } =>
+                format!("Errore nella dichiarazione {}: la costante {} è usata con {} universi invece di {}",
+                    self.fmt_name(*declar), self.fmt_name(*costante), dati, attesi),
+            LooseBoundVar { declar, in_val, range } =>
+                format!("Errore nella dichiarazione {}: {} non è chiuso, contiene la variabile libera #{}",
+                    self.fmt_name(*declar), if *in_val { "il valore" } else { "il tipo" }, range - 1),
+            TypeMismatch { declar, ty, val_ty, universi } =>
+                self.fmt_mismatch(*declar, "tipo della definizione non coincide", ("tipo dichiarato", *ty), ("tipo del valore", *val_ty), universi),
+            LetTypeMismatch { declar, ty, val_ty, universi } =>
+                self.fmt_mismatch(*declar, "tipo di una let non coincide", ("tipo dichiarato", *ty), ("tipo del valore", *val_ty), universi),
+            AppTypeMismatch { declar, param_ty, arg_ty, universi } =>
+                self.fmt_mismatch(*declar, "argomento di tipo sbagliato in un'applicazione", ("tipo del parametro", *param_ty), ("tipo dell'argomento", *arg_ty), universi),
+            FunctionExpected { declar, fun_ty } =>
+                format!("Errore nella dichiarazione {}: applicazione di un termine che non è una funzione.\n  tipo del termine:\n    {}",
+                    self.fmt_name(*declar), self.fmt_expr_rientro(*fun_ty, 4)),
+            SortExpected { declar, e, ty } =>
+                format!("Errore nella dichiarazione {}: {} è usato come tipo, ma ha tipo {}",
+                    self.fmt_name(*declar), self.fmt_expr(*e), self.fmt_expr(*ty)),
+            BadProjection { declar, proj, motivo } =>
+                format!("Errore nella dichiarazione {}: proiezione non valida, {}.\n  proiezione:\n    {}",
+                    self.fmt_name(*declar), motivo, self.fmt_expr_rientro(*proj, 4)),
+            UndeclaredUniverseParam { declar, universe } =>
+                format!("Errore nella dichiarazione {}: l'universo {} usa parametri non dichiarati",
+                    self.fmt_name(*declar), self.fmt_universe(*universe)),
+            DeclarationTimedOut { declar, passi, tempo } =>
+                format!("Errore nella dichiarazione {}: {} dopo {} passi, controllo interrotto",
+                    self.fmt_name(*declar), if *tempo { "tempo massimo superato" } else { "limite di passi superato" }, passi),
+            DeclarationTooDeep { declar, profondita, ricorsione } =>
+                format!("Errore nella dichiarazione {}: {} ({} livelli), controllo interrotto",
+                    self.fmt_name(*declar), if *ricorsione { "ricorsione troppo profonda" } else { "termine troppo profondo" }, profondita),
+        }
+    }
+    fn fmt_mismatch(&self, declar: NamePtr<'t>, titolo: &str, (nome_a, a): (&str, ExprPtr<'t>), (nome_b, b): (&str, ExprPtr<'t>),
+        universi: &Option<FallimentoUniverso<'t>>) -> String {
+        let mut out = format!("Errore nella dichiarazione {}: {}.\n  {}:\n    {}\n  {}:\n    {}",
+            self.fmt_name(declar), titolo, nome_a, self.fmt_expr_rientro(a, 4), nome_b, self.fmt_expr_rientro(b, 4));
+        if let Some(f) = universi {
+            out += &format!("\n  vincolo di universo violato: {}", self.fmt_fallimento(f));
+        }
+        out
+    }
+}
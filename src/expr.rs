@@ -1,14 +1,11 @@
-use std::sync::Mutex;
 use std::hash::{Hash, Hasher};
-use crate::util::{Ptr, ExportFile};
+use crate::util::{Ptr, TypeChecker, FxIndexMap, FxIndexSet, new_fx_index_map, new_fx_index_set};
 use crate::hash64;
 use crate::universe::{UparamsPtr, UniversePtr};
 use crate::name::NamePtr;
 use Expr::*;
 
 
-static COUNTER_FREEVAR: Mutex<u32> = Mutex::new(0);
-
 pub const VAR_HASH: u64 = 20;
 pub const SORT_HASH: u64 = 21;
 pub const CONST_HASH: u64 = 22;
@@ -24,17 +21,17 @@ pub const FREE_VAR_HASH: u64 = 30;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Expr<'a> {
-    Var { hash: u64, dbj_idx: u32, },
-    FreeVar {hash: u64, idx: u32, ty: ExprPtr<'a>, },
-    Sort { hash: u64, universe: UniversePtr<'a>, },
-    Const { hash: u64, name: NamePtr<'a>, universes: UparamsPtr<'a>, },
-    App { hash: u64, fun: ExprPtr<'a>, arg: ExprPtr<'a>,  },
-    Lambda { hash: u64, name: NamePtr<'a>,  ty: ExprPtr<'a>, body: ExprPtr<'a>,  },
-    Pi { hash: u64, name: NamePtr<'a>,  ty: ExprPtr<'a>, body: ExprPtr<'a>,  },
-    Let { hash: u64, name: NamePtr<'a>, ty: ExprPtr<'a>, val: ExprPtr<'a>, body: ExprPtr<'a>,  },
-    Proj { hash: u64, name: NamePtr<'a>, idx: u32, structure: ExprPtr<'a>,  },
-    NatLit { hash: u64, val: u128, },
-    StrLit { hash: u64, val: String, },
+    Var { hash: u64, info: ExprInfo, dbj_idx: u32, },
+    FreeVar {hash: u64, info: ExprInfo, idx: u32, ty: ExprPtr<'a>, },
+    Sort { hash: u64, info: ExprInfo, universe: UniversePtr<'a>, },
+    Const { hash: u64, info: ExprInfo, name: NamePtr<'a>, universes: UparamsPtr<'a>, },
+    App { hash: u64, info: ExprInfo, fun: ExprPtr<'a>, arg: ExprPtr<'a>,  },
+    Lambda { hash: u64, info: ExprInfo, name: NamePtr<'a>,  ty: ExprPtr<'a>, body: ExprPtr<'a>,  },
+    Pi { hash: u64, info: ExprInfo, name: NamePtr<'a>,  ty: ExprPtr<'a>, body: ExprPtr<'a>,  },
+    Let { hash: u64, info: ExprInfo, name: NamePtr<'a>, ty: ExprPtr<'a>, val: ExprPtr<'a>, body: ExprPtr<'a>,  },
+    Proj { hash: u64, info: ExprInfo, name: NamePtr<'a>, idx: u32, structure: ExprPtr<'a>,  },
+    NatLit { hash: u64, info: ExprInfo, val: u128, },
+    StrLit { hash: u64, info: ExprInfo, val: String, },
 }
 
 impl<'a> Expr<'a> {
@@ -45,6 +42,49 @@ impl<'a> Expr<'a> {
             | StrLit { hash, .. } | NatLit { hash, .. } | Proj { hash, .. } => *hash,
         }
     }
+
+    pub fn get_info(&self) -> ExprInfo {
+        match self {
+            Var { info, .. } | FreeVar { info, .. } | Sort { info, .. } | Const { info, .. }
+            | App { info, .. } | Pi { info, .. } | Lambda { info, .. } | Let { info, .. } 
+            | StrLit { info, .. } | NatLit { info, .. } | Proj { info, .. } => *info,
+        }
+    }
+}
+
+// Dati calcolati alla costruzione di ogni Expr a partire dai figli.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExprInfo {
+    // 1 + il massimo indice di de Bruijn libero, 0 se l'espressione è chiusa
+    pub loose_bvar_range: u32,
+    pub has_fvar: bool,
+    pub has_param: bool,
+    // numero di nodi dell'albero, senza tenere conto della condivisione
+    pub size: u64,
+    // comprende la profondità degli universi di Sort e Const, che le visite degli universi
+    // attraversano ricorsivamente
+    pub depth: u32,
+}
+
+impl ExprInfo {
+    pub const LEAF: ExprInfo = ExprInfo { loose_bvar_range: 0, has_fvar: false, has_param: false, size: 1, depth: 1 };
+
+    fn node(children: &[ExprInfo]) -> ExprInfo {
+        let mut out = ExprInfo::LEAF;
+        for c in children {
+            out.loose_bvar_range = out.loose_bvar_range.max(c.loose_bvar_range);
+            out.has_fvar |= c.has_fvar;
+            out.has_param |= c.has_param;
+            out.size = out.size.saturating_add(c.size);
+            out.depth = out.depth.max(c.depth + 1);
+        }
+        out
+    }
+
+    // il corpo di un binder ha una variabile legata in più
+    fn binder(self) -> ExprInfo {
+        ExprInfo { loose_bvar_range: self.loose_bvar_range.saturating_sub(1), ..self }
+    }
 }
 
 impl<'a> Hash for Expr<'a> {
@@ -53,67 +93,93 @@ impl<'a> Hash for Expr<'a> {
 
 pub type ExprPtr<'a> = Ptr<&'a Expr<'a>>;
 
-impl<'t> ExportFile<'t> {
+impl<'e, 't> TypeChecker<'e, 't> {
     
+    pub fn expr_info(&self, e: ExprPtr<'t>) -> ExprInfo {
+        self.get_expr(e).get_info()
+    }
+
     pub fn var(&mut self, dbj_idx: u32) -> ExprPtr<'t> {
         let hash = hash64!(VAR_HASH, dbj_idx);
-        self.alloc_expr(Var { dbj_idx, hash })
+        let info = ExprInfo { loose_bvar_range: dbj_idx + 1, ..ExprInfo::LEAF };
+        self.alloc_expr(Var { dbj_idx, hash, info })
     }
     pub fn free_var(&mut self, ty: ExprPtr<'t>) -> ExprPtr<'t> {
-        let mut c = COUNTER_FREEVAR.lock().unwrap();
-        let idx = *c;
-        *c += 1;
+        let idx = self.next_free_var;
+        self.next_free_var += 1;
         let hash = hash64!(FREE_VAR_HASH, idx, ty);
-        self.alloc_expr(FreeVar { idx, ty, hash })
+        let info = ExprInfo { has_fvar: true, has_param: self.expr_info(ty).has_param, ..ExprInfo::LEAF };
+        self.alloc_expr(FreeVar { idx, ty, hash, info })
     }
 
     pub fn sort(&mut self, universe: UniversePtr<'t>) -> ExprPtr<'t> {
         let hash = hash64!(SORT_HASH, universe);
-        self.alloc_expr(Sort { universe, hash })
+        let u = self.info_universo(universe);
+        let info = ExprInfo { has_param: u.has_param, depth: u.profondita.saturating_add(1), ..ExprInfo::LEAF };
+        self.alloc_expr(Sort { universe, hash, info })
     }
 
     pub fn mk_const(&mut self, name: NamePtr<'t>, universes: UparamsPtr<'t>) -> ExprPtr<'t> {
         let hash = hash64!(CONST_HASH, name, universes);
-        self.alloc_expr(Const { name, universes, hash })
+        let us = self.read_uparams(universes).iter().map(|&u| self.info_universo(u)).collect::<Vec<_>>();
+        let has_param = us.iter().any(|u| u.has_param);
+        let depth = us.iter().map(|u| u.profondita).max().unwrap_or(0).saturating_add(1);
+        let info = ExprInfo { has_param, depth, ..ExprInfo::LEAF };
+        self.alloc_expr(Const { name, universes, hash, info })
     }
 
     pub fn app(&mut self, fun: ExprPtr<'t>, arg: ExprPtr<'t>) -> ExprPtr<'t> {
         let hash = hash64!(APP_HASH, fun, arg);
-        self.alloc_expr(App { fun, arg, hash })
+        let info = ExprInfo::node(&[self.expr_info(fun), self.expr_info(arg)]);
+        self.alloc_expr(App { fun, arg, hash, info })
     }
 
     pub fn lambda( &mut self, name: NamePtr<'t>, ty: ExprPtr<'t>, body: ExprPtr<'t> ) -> ExprPtr<'t> {
         let hash = hash64!(LAMBDA_HASH, name, ty, body);
-        self.alloc_expr(Lambda { name, ty, body, hash })
+        let info = ExprInfo::node(&[self.expr_info(ty), self.expr_info(body).binder()]);
+        self.alloc_expr(Lambda { name, ty, body, hash, info })
     }
 
     pub fn pi(&mut self, name: NamePtr<'t>, ty: ExprPtr<'t>, body: ExprPtr<'t>, ) -> ExprPtr<'t> {
         let hash = hash64!(PI_HASH, name, ty, body);
-        self.alloc_expr(Pi { name, ty, body, hash })
+        let info = ExprInfo::node(&[self.expr_info(ty), self.expr_info(body).binder()]);
+        self.alloc_expr(Pi { name, ty, body, hash, info })
     }
 
     pub fn mk_let( &mut self, name: NamePtr<'t>, ty: ExprPtr<'t>, val: ExprPtr<'t>, body: ExprPtr<'t> ) -> ExprPtr<'t> {
         let hash = hash64!(LET_HASH, name, ty, val, body);
-        self.alloc_expr(Let { name, ty, val, body, hash })
+        let info = ExprInfo::node(&[self.expr_info(ty), self.expr_info(val), self.expr_info(body).binder()]);
+        self.alloc_expr(Let { name, ty, val, body, hash, info })
     }
 
     pub fn proj(&mut self, name: NamePtr<'t>, idx: u32, structure: ExprPtr<'t>) -> ExprPtr<'t> {
         let hash = hash64!(PROJ_HASH, name, idx, structure);
-        self.alloc_expr(Proj { name, idx, structure, hash })
+        let info = ExprInfo::node(&[self.expr_info(structure)]);
+        self.alloc_expr(Proj { name, idx, structure, hash, info })
     }
 
     pub fn nat_lit(&mut self, val: u128) -> ExprPtr<'t> {
         let hash = hash64!(NAT_LIT_HASH, val);
-        self.alloc_expr(NatLit { val, hash })
+        self.alloc_expr(NatLit { val, hash, info: ExprInfo::LEAF })
     }
     
     pub fn str_lit(&mut self, val: String) -> ExprPtr<'t> {
         let hash = hash64!(STR_LIT_HASH, val);
-        self.alloc_expr(StrLit { val, hash })
+        self.alloc_expr(StrLit { val, hash, info: ExprInfo::LEAF })
     }
     
     pub fn subst_expr_universes(&mut self, e: ExprPtr<'t>, ups_dec: UparamsPtr<'t>, ups_const: UparamsPtr<'t>) -> ExprPtr<'t> {
-        match self.read_expr(e) {
+        self.subst_expr_universes_aux(e, ups_dec, ups_const, &mut new_fx_index_map())
+    }
+
+    fn subst_expr_universes_aux(&mut self, e: ExprPtr<'t>, ups_dec: UparamsPtr<'t>, ups_const: UparamsPtr<'t>, cache: &mut FxIndexMap<ExprPtr<'t>, ExprPtr<'t>>) -> ExprPtr<'t> {
+        if !self.expr_info(e).has_param {
+            return e
+        }
+        if let Some(out) = cache.get(&e) {
+            return *out
+        }
+        let out = match self.read_expr(e) {
             Var { .. } | NatLit { .. } | StrLit { .. } => e,
             FreeVar { .. } => panic!("subst di universi su una freeVar"),
             Sort { universe, .. } => {
@@ -125,118 +191,189 @@ impl<'t> ExportFile<'t> {
                 self.mk_const(name, universes)
             }
             App { fun, arg, .. } => {
-                let fun = self.subst_expr_universes(fun, ups_dec, ups_const);
-                let arg = self.subst_expr_universes(arg, ups_dec, ups_const);
+                let fun = self.subst_expr_universes_aux(fun, ups_dec, ups_const, cache);
+                let arg = self.subst_expr_universes_aux(arg, ups_dec, ups_const, cache);
                 self.app(fun, arg)
             }
             Pi { name,  ty, body, .. } => {
-                let ty = self.subst_expr_universes(ty, ups_dec, ups_const);
-                let body = self.subst_expr_universes(body, ups_dec, ups_const);
+                let ty = self.subst_expr_universes_aux(ty, ups_dec, ups_const, cache);
+                let body = self.subst_expr_universes_aux(body, ups_dec, ups_const, cache);
                 self.pi(name,  ty, body)
             }
             Lambda { name,  ty, body, .. } => {
-                let ty = self.subst_expr_universes(ty, ups_dec, ups_const);
-                let body = self.subst_expr_universes(body, ups_dec, ups_const);
+                let ty = self.subst_expr_universes_aux(ty, ups_dec, ups_const, cache);
+                let body = self.subst_expr_universes_aux(body, ups_dec, ups_const, cache);
                 self.lambda(name,  ty, body)
             }
             Let { name, ty, val, body, .. } => {
-                let ty = self.subst_expr_universes(ty, ups_dec, ups_const);
-                let val = self.subst_expr_universes(val, ups_dec, ups_const);
-                let body = self.subst_expr_universes(body, ups_dec, ups_const);
+                let ty = self.subst_expr_universes_aux(ty, ups_dec, ups_const, cache);
+                let val = self.subst_expr_universes_aux(val, ups_dec, ups_const, cache);
+                let body = self.subst_expr_universes_aux(body, ups_dec, ups_const, cache);
                 self.mk_let(name, ty, val, body)
             }
             Proj { name, idx, structure, .. } => {
-                let structure = self.subst_expr_universes(structure, ups_dec, ups_const);
+                let structure = self.subst_expr_universes_aux(structure, ups_dec, ups_const, cache);
                 self.proj(name, idx, structure)
             }
-        }
+        };
+        cache.insert(e, out);
+        out
     }
     
     pub fn abstr(&mut self, e: ExprPtr<'t>, val: ExprPtr<'t>, deph: u32) -> ExprPtr<'t> {
-        match self.read_expr(e) {
+        self.abstr_many(e, &[val], deph)
+    }
+
+    // sostituisce fvars[i] con la variabile legata dal binder i-esimo, il primo è il più esterno
+    pub fn abstr_many(&mut self, e: ExprPtr<'t>, fvars: &[ExprPtr<'t>], deph: u32) -> ExprPtr<'t> {
+        if fvars.is_empty() {
+            return e
+        }
+        self.abstr_aux(e, fvars, deph, &mut new_fx_index_map())
+    }
+
+    fn abstr_aux(&mut self, e: ExprPtr<'t>, fvars: &[ExprPtr<'t>], deph: u32, cache: &mut FxIndexMap<(ExprPtr<'t>, u32), ExprPtr<'t>>) -> ExprPtr<'t> {
+        let info = self.expr_info(e);
+        if !info.has_fvar && info.loose_bvar_range <= deph {
+            return e
+        }
+        if let Some(out) = cache.get(&(e, deph)) {
+            return *out
+        }
+        let n = fvars.len() as u32;
+        let out = match self.read_expr(e) {
             FreeVar { .. } => {
-                if e == val {
-                    return self.var(deph)
+                match fvars.iter().position(|f| *f == e) {
+                    Some(i) => self.var(deph + n - 1 - i as u32),
+                    None => e,
                 }
-                return e
             }
             
             Var { dbj_idx, .. } => {
                 if dbj_idx >= deph {
-                    return self.var(dbj_idx+1);
+                    self.var(dbj_idx + n)
                 } else {
-                    return e;
+                    e
                 }
             }
             
             NatLit{..} | StrLit{..} | Sort { .. } | Const { .. } => e, 
             
             App { fun, arg, .. } =>{
-                let f = self.abstr(fun, val, deph);
-                let a = self.abstr(arg, val, deph);
+                let f = self.abstr_aux(fun, fvars, deph, cache);
+                let a = self.abstr_aux(arg, fvars, deph, cache);
                 self.app(f, a)
             }
             Lambda { name, ty, body, ..  } => {
-                let t = self.abstr(ty, val, deph);
-                let b = self.abstr(body, val, deph + 1);
+                let t = self.abstr_aux(ty, fvars, deph, cache);
+                let b = self.abstr_aux(body, fvars, deph + 1, cache);
                 self.lambda(name, t, b)
             }
             Pi { name, ty, body, ..  } => {
-                let t = self.abstr(ty, val, deph);
-                let b = self.abstr(body, val, deph + 1);
+                let t = self.abstr_aux(ty, fvars, deph, cache);
+                let b = self.abstr_aux(body, fvars, deph + 1, cache);
                 self.pi(name, t, b)
             }
-            Let { name, ty, val: val_let, body, ..  } => {
-                let t = self.abstr(ty, val, deph);
-                let v = self.abstr(val_let, val, deph);
-                let b = self.abstr(body, val, deph + 1);
+            Let { name, ty, val, body, ..  } => {
+                let t = self.abstr_aux(ty, fvars, deph, cache);
+                let v = self.abstr_aux(val, fvars, deph, cache);
+                let b = self.abstr_aux(body, fvars, deph + 1, cache);
                 self.mk_let(name, t, v, b)
             }
             Proj { name, idx, structure, ..  } => {
-                let s = self.abstr(structure, val, deph);
+                let s = self.abstr_aux(structure, fvars, deph, cache);
                 self.proj(name, idx, s)
             }
-        }
+        };
+        cache.insert((e, deph), out);
+        out
     }
     
     pub fn inst(&mut self, e: ExprPtr<'t>, val: ExprPtr<'t>, deph: u32) -> ExprPtr<'t> {
-        match self.read_expr(e) {
+        self.inst_many(e, &[val], deph)
+    }
+
+    // sostituisce in un colpo solo le variabili legate da vals.len() binder, vals[0] va al più esterno
+    pub fn inst_many(&mut self, e: ExprPtr<'t>, vals: &[ExprPtr<'t>], deph: u32) -> ExprPtr<'t> {
+        if vals.is_empty() {
+            return e
+        }
+        let span = self.apri_span();
+        let out = self.inst_aux(e, vals, deph, &mut new_fx_index_map());
+        self.chiudi_span(span, || "inst".to_string(), || format!("\"e\": {}, \"vals\": {}", e.idx, vals.len()));
+        out
+    }
+
+    fn inst_aux(&mut self, e: ExprPtr<'t>, vals: &[ExprPtr<'t>], deph: u32, cache: &mut FxIndexMap<(ExprPtr<'t>, u32), ExprPtr<'t>>) -> ExprPtr<'t> {
+        if self.expr_info(e).loose_bvar_range <= deph {
+            return e
+        }
+        if let Some(out) = cache.get(&(e, deph)) {
+            return *out
+        }
+        let n = vals.len() as u32;
+        let out = match self.read_expr(e) {
             Var { dbj_idx, .. } => {
-                if dbj_idx == deph {
-                    return val; 
-                } else if dbj_idx > deph {
-                    return self.var(dbj_idx-1);
+                if dbj_idx < deph + n {
+                    vals[(deph + n - 1 - dbj_idx) as usize]
                 } else {
-                    return e;
+                    self.var(dbj_idx - n)
                 }
             }
             NatLit{..} | StrLit{..} | FreeVar { .. } | Sort { .. } | Const { .. } => e, 
             App { fun, arg, .. } =>{
-                let f = self.inst(fun, val, deph);
-                let a = self.inst(arg, val, deph);
+                let f = self.inst_aux(fun, vals, deph, cache);
+                let a = self.inst_aux(arg, vals, deph, cache);
                 self.app(f, a)
             }
             Lambda { name, ty, body, ..  } => {
-                let t = self.inst(ty, val, deph);
-                let b = self.inst(body, val, deph + 1);
+                let t = self.inst_aux(ty, vals, deph, cache);
+                let b = self.inst_aux(body, vals, deph + 1, cache);
                 self.lambda(name, t, b)
             }
             Pi { name, ty, body, ..  } => {
-                let t = self.inst(ty, val, deph);
-                let b = self.inst(body, val, deph + 1);
+                let t = self.inst_aux(ty, vals, deph, cache);
+                let b = self.inst_aux(body, vals, deph + 1, cache);
                 self.pi(name, t, b)
             }
-            Let { name, ty, val: val_let, body, ..  } => {
-                let t = self.inst(ty, val, deph);
-                let v = self.inst(val_let, val, deph);
-                let b = self.inst(body, val, deph + 1);
+            Let { name, ty, val, body, ..  } => {
+                let t = self.inst_aux(ty, vals, deph, cache);
+                let v = self.inst_aux(val, vals, deph, cache);
+                let b = self.inst_aux(body, vals, deph + 1, cache);
                 self.mk_let(name, t, v, b)
             }
             Proj { name, idx, structure, ..  } => {
-                let s = self.inst(structure, val, deph);
+                let s = self.inst_aux(structure, vals, deph, cache);
                 self.proj(name, idx, s)
             }
+        };
+        cache.insert((e, deph), out);
+        out
+    }
+
+    pub fn loose_bvar_range(&self, e: ExprPtr<'t>) -> u32 {
+        self.expr_info(e).loose_bvar_range
+    }
+
+    // tutte le costanti nominate in e, compresi i tipi usati dalle proiezioni
+    pub fn costanti(&self, e: ExprPtr<'t>, out: &mut FxIndexSet<NamePtr<'t>>) {
+        let mut visti = new_fx_index_set();
+        let mut pila = vec![e];
+        while let Some(e) = pila.pop() {
+            if !visti.insert(e) {
+                continue
+            }
+            match self.read_expr(e) {
+                Var { .. } | FreeVar { .. } | Sort { .. } | NatLit { .. } | StrLit { .. } => {}
+                Const { name, .. } => { out.insert(name); }
+                App { fun, arg, .. } => pila.extend([fun, arg]),
+                Lambda { ty, body, .. } | Pi { ty, body, .. } => pila.extend([ty, body]),
+                Let { ty, val, body, .. } => pila.extend([ty, val, body]),
+                Proj { name, structure, .. } => {
+                    out.insert(name);
+                    pila.push(structure);
+                }
+            }
         }
     }
 }
-
@@ -1,8 +1,16 @@
+pub mod axioms;
 pub mod declar;
+pub mod error;
 pub mod expr;
+pub mod log;
 pub mod universe;
 pub mod name;
 pub mod parser;
+pub mod pretty;
+pub mod profile;
+pub mod report;
+pub mod select;
+pub mod stats;
 pub mod tc;
 pub mod util;
 
@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Livello {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sottosistema {
+    Parser,
+    Infer,
+    Whnf,
+    DefEq,
+    Universe,
+}
+
+const SOTTOSISTEMI: [(Sottosistema, &str); 5] = [
+    (Sottosistema::Parser, "parser"),
+    (Sottosistema::Infer, "infer"),
+    (Sottosistema::Whnf, "whnf"),
+    (Sottosistema::DefEq, "def_eq"),
+    (Sottosistema::Universe, "universe"),
+];
+
+// livello massimo stampato per ogni sottosistema, Off all'avvio
+static LIVELLI: [AtomicU8; 5] = [const { AtomicU8::new(Livello::Off as u8) }; 5];
+
+impl Livello {
+    fn da_str(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Livello::Off),
+            "error" => Some(Livello::Error),
+            "warn" => Some(Livello::Warn),
+            "info" => Some(Livello::Info),
+            "debug" => Some(Livello::Debug),
+            "trace" => Some(Livello::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl Sottosistema {
+    pub fn nome(self) -> &'static str {
+        SOTTOSISTEMI[self as usize].1
+    }
+}
+
+pub fn abilitato(s: Sottosistema, l: Livello) -> bool {
+    l as u8 <= LIVELLI[s as usize].load(Ordering::Relaxed)
+}
+
+pub fn imposta(s: Sottosistema, l: Livello) {
+    LIVELLI[s as usize].store(l as u8, Ordering::Relaxed);
+}
+
+// spec come "debug" (tutti i sottosistemi) o "def_eq=trace,universe=debug"
+pub fn configura(spec: &str) -> Result<(), String> {
+    for parte in spec.split(',').filter(|p| !p.is_empty()) {
+        match parte.split_once('=') {
+            None => {
+                let l = Livello::da_str(parte).ok_or_else(|| format!("livello di log {:?} sconosciuto", parte))?;
+                for (s, _) in SOTTOSISTEMI {
+                    imposta(s, l);
+                }
+            }
+            Some((nome, livello)) => {
+                let (s, _) = SOTTOSISTEMI.iter().find(|(_, n)| *n == nome)
+                    .ok_or_else(|| format!("sottosistema di log {:?} sconosciuto", nome))?;
+                let l = Livello::da_str(livello).ok_or_else(|| format!("livello di log {:?} sconosciuto", livello))?;
+                imposta(*s, l);
+            }
+        }
+    }
+    Ok(())
+}
+
+// gli argomenti vengono formattati solo se il livello è abilitato
+#[macro_export]
+macro_rules! log {
+    ($sotto:ident, $livello:ident, $($arg:tt)*) => {
+        if $crate::log::abilitato($crate::log::Sottosistema::$sotto, $crate::log::Livello::$livello) {
+            eprintln!("[{} {:?}] {}", $crate::log::Sottosistema::$sotto.nome(), $crate::log::Livello::$livello, format_args!($($arg)*));
+        }
+    };
+}
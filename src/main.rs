@@ -1,21 +1,169 @@
 use std::error::Error;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::resume_unwind;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use typecheck_rust::axioms::{assiomi_non_consentiti, stampa_assiomi, stampa_non_consentiti};
 use typecheck_rust::parser::read_export_file;
+use typecheck_rust::log;
+use typecheck_rust::profile::{Profilo, Traccia, scrivi_traccia};
+use typecheck_rust::report::scrivi_json;
+use typecheck_rust::select::{Selezione, leggi_nomi};
+use typecheck_rust::stats::stampa_statistiche;
+use typecheck_rust::tc::{Limiti, PILA};
+use typecheck_rust::util::TypeChecker;
+
+struct Config {
+    path: PathBuf,
+    jobs: usize,
+    nomi: Vec<String>,
+    prefissi: Vec<String>,
+    esclusi: Vec<String>,
+    json: Option<PathBuf>,
+    // quante dichiarazioni più lente mostrare nelle statistiche
+    stats: Option<usize>,
+    traccia: Option<PathBuf>,
+    profondita_traccia: u32,
+    limiti: Limiti,
+    assiomi: bool,
+    // None se non c'è da verificare quali assiomi sono usati
+    consentiti: Option<Vec<String>>,
+}
 
 fn main() -> Result<(), Box<dyn Error>> {
     let mut args = std::env::args();
     args.next();
 
-    match args.next().as_ref() {
+    let mut path = None;
+    let mut jobs = 1;
+    let mut nomi = Vec::new();
+    let mut prefissi = Vec::new();
+    let mut esclusi = Vec::new();
+    let mut json = None;
+    let mut stats = None;
+    let mut traccia = None;
+    let mut profondita_traccia = 32;
+    let mut limiti = Limiti::default();
+    let mut assiomi = false;
+    let mut consentiti: Option<Vec<String>> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--jobs" => {
+                jobs = args.next()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or("--jobs richiede un numero di thread positivo")?;
+            }
+            "--log" => {
+                let spec = args.next().ok_or("--log richiede un livello, per esempio def_eq=trace,universe=debug")?;
+                log::configura(&spec)?;
+            }
+            "--only" => nomi.push(args.next().ok_or("--only richiede il nome di una dichiarazione")?),
+            "--prefix" => prefissi.push(args.next().ok_or("--prefix richiede un prefisso, per esempio Nat")?),
+            "--exclude" => esclusi.push(args.next().ok_or("--exclude richiede un prefisso")?),
+            "--names-file" => {
+                let file = args.next().ok_or("--names-file richiede un file con un nome per riga")?;
+                nomi.extend(leggi_nomi(&fs::read_to_string(&file)?));
+            }
+            "--json" => json = Some(PathBuf::from(args.next().ok_or("--json richiede il percorso del report")?)),
+            "--stats" => {
+                stats = Some(args.next()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .ok_or("--stats richiede il numero di dichiarazioni da mostrare")?);
+            }
+            "--trace" => traccia = Some(PathBuf::from(args.next().ok_or("--trace richiede il percorso del file di traccia")?)),
+            "--trace-depth" => {
+                profondita_traccia = args.next()
+                    .and_then(|n| n.parse::<u32>().ok())
+                    .ok_or("--trace-depth richiede un numero")?;
+            }
+            "--max-steps" => {
+                limiti.passi = Some(args.next()
+                    .and_then(|n| n.parse::<u64>().ok())
+                    .ok_or("--max-steps richiede il numero massimo di passi per dichiarazione")?);
+            }
+            "--timeout" => {
+                limiti.tempo = Some(args.next()
+                    .and_then(|s| Duration::try_from_secs_f64(s.parse::<f64>().ok()?).ok())
+                    .ok_or("--timeout richiede i secondi massimi per dichiarazione")?);
+            }
+            "--axioms" => assiomi = true,
+            "--allowed-axioms" => {
+                let lista = args.next().ok_or("--allowed-axioms richiede una lista di assiomi, per esempio propext,Quot.sound,Classical.choice")?;
+                consentiti.get_or_insert_default().extend(lista.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string));
+            }
+            opzione if opzione.starts_with("--") => return Err(format!("opzione sconosciuta {}", opzione).into()),
+            _ => {
+                if let Some(primo) = path.replace(arg) {
+                    return Err(format!("è richiesto un solo export file, trovati {} e {}", primo, path.unwrap()).into())
+                }
+            }
+        }
+    }
+
+    match path {
         None => Err(Box::from("È richiesto un export file")),
-        Some(p) => use_config(Path::new(p)),
+        Some(p) => use_config(Config { path: PathBuf::from(p), jobs, nomi, prefissi, esclusi, json, stats, traccia, profondita_traccia, limiti, assiomi, consentiti }),
     }?;
 
     Ok(())
 }
 
-fn use_config(config_path: &Path) -> Result<(), Box<dyn Error>> {
-    let mut file = read_export_file(config_path)?;
-    file.check_all_declars();
+fn use_config(config: Config) -> Result<(), Box<dyn Error>> {
+    let env = read_export_file(&config.path)?;
+    let indici = Selezione::new(&env, &config.nomi, &config.prefissi, &config.esclusi)?.indici(&env);
+    let consentiti = match &config.consentiti {
+        Some(nomi) => Some(nomi.iter()
+            .map(|s| env.find_name(s).ok_or_else(|| format!("assioma {} non trovato nell'export file", s)))
+            .collect::<Result<Vec<_>, _>>()?),
+        None => None,
+    };
+    let traccia = config.traccia.is_some().then(|| Traccia::nuova(config.profondita_traccia));
+    let (risultati, profili) = if config.jobs > 1 {
+        env.check_declars_parallel(&indici, config.jobs, config.limiti, traccia)
+    } else {
+        // anche da solo il controllo ha bisogno di più stack di quello del thread principale
+        thread::scope(|s| {
+            let controllo = thread::Builder::new().stack_size(PILA).spawn_scoped(s, || {
+                let mut tc = TypeChecker::new(&env);
+                tc.limiti = config.limiti;
+                tc.profilo = traccia.map(|t| Profilo::nuovo(t, 1));
+                (tc.check_declars(&indici), tc.profilo.into_iter().collect::<Vec<_>>())
+            })?;
+            Ok::<_, io::Error>(controllo.join().unwrap_or_else(|p| resume_unwind(p)))
+        })?
+    };
+    if let Some(n) = config.stats {
+        stampa_statistiche(&env, &risultati, n);
+    }
+    let assiomi = (config.json.is_some() || config.assiomi || consentiti.is_some()).then(|| env.assiomi(config.jobs));
+    if config.assiomi {
+        stampa_assiomi(&env, &indici, assiomi.as_ref().unwrap());
+    }
+    if let Some(json) = &config.json {
+        let mut out = BufWriter::new(File::create(json)?);
+        scrivi_json(&env, &risultati, assiomi.as_ref().unwrap(), &mut out)?;
+        out.flush()?;
+    }
+    if let Some(traccia) = &config.traccia {
+        let mut out = BufWriter::new(File::create(traccia)?);
+        scrivi_traccia(&mut out, &profili)?;
+        out.flush()?;
+    }
+    let non_consentiti = match &consentiti {
+        Some(consentiti) => assiomi_non_consentiti(&env, &indici, assiomi.as_ref().unwrap(), consentiti),
+        None => Vec::new(),
+    };
+    stampa_non_consentiti(&env, &non_consentiti);
+    // l'uscita con errore segnala le dichiarazioni rifiutate a chi usa il programma in uno script
+    let errori = risultati.iter().filter(|r| r.errore.is_some()).count();
+    if errori > 0 {
+        return Err(format!("{} dichiarazioni non superano il controllo", errori).into())
+    }
+    if !non_consentiti.is_empty() {
+        return Err(format!("{} dichiarazioni usano assiomi non consentiti", non_consentiti.len()).into())
+    }
     Ok(())
 }
@@ -1,5 +1,7 @@
+use std::fmt;
 use std::hash::{Hash, Hasher};
-use crate::util::{ Ptr, ExportFile};
+use crate::declar::Declar;
+use crate::util::{ Ptr, Environment, TypeChecker};
 use crate::hash64;
 use Name::*;
 
@@ -29,7 +31,7 @@ impl<'a> Hash for Name<'a> {
 
 pub type NamePtr<'a> = Ptr<&'a Name<'a>>;
 
-impl<'a> ExportFile<'a> {
+impl<'e, 'a> TypeChecker<'e, 'a> {
     
     pub fn anonymous(&self) -> NamePtr<'a> { Ptr::from(0) }
     
@@ -43,3 +45,143 @@ impl<'a> ExportFile<'a> {
     }
 }
 
+
+// accesso in lettura ai nomi, sia dall'environment che dal type checker
+pub trait LeggiNomi<'a> {
+    fn leggi_nome(&self, n: NamePtr<'a>) -> &Name<'a>;
+
+    fn display_name(&self, name: NamePtr<'a>) -> NameDisplay<'_, 'a> where Self: Sized {
+        NameDisplay { nomi: self, name }
+    }
+}
+
+impl<'a> LeggiNomi<'a> for Environment<'a> {
+    fn leggi_nome(&self, n: NamePtr<'a>) -> &Name<'a> {
+        self.names.get_index(n.idx).unwrap()
+    }
+}
+
+impl<'e, 'a> LeggiNomi<'a> for TypeChecker<'e, 'a> {
+    fn leggi_nome(&self, n: NamePtr<'a>) -> &Name<'a> {
+        self.get_name(n)
+    }
+}
+
+pub struct NameDisplay<'n, 'a> {
+    nomi: &'n dyn LeggiNomi<'a>,
+    name: NamePtr<'a>,
+}
+
+// come isLetterLike di Lean: greco (tranne λ, Π, Σ), simboli letterali e matematici
+fn letter_like(c: char) -> bool {
+    let c = c as u32;
+    (0x3b1..=0x3c9).contains(&c) && c != 0x3bb
+        || (0x391..=0x3a9).contains(&c) && c != 0x3a0 && c != 0x3a3
+        || (0x3ca..=0x3fb).contains(&c)
+        || (0x1f00..=0x1ffe).contains(&c)
+        || (0x2100..=0x214f).contains(&c)
+        || (0x1d49c..=0x1d59f).contains(&c)
+}
+
+fn pedice(c: char) -> bool {
+    let c = c as u32;
+    (0x207f..=0x2089).contains(&c) || (0x2090..=0x209c).contains(&c) || (0x1d62..=0x1d6a).contains(&c)
+}
+
+fn id_first(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || letter_like(c)
+}
+
+fn id_rest(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '!' | '?') || letter_like(c) || pedice(c)
+}
+
+// una componente che non è un identificatore va tra «»
+fn scrivi_componente(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    let mut cs = s.chars();
+    match cs.next() {
+        Some(c) if id_first(c) && cs.all(id_rest) => write!(f, "{}", s),
+        _ => write!(f, "«{}»", s),
+    }
+}
+
+impl fmt::Display for NameDisplay<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parti = Vec::new();
+        let mut n = self.name;
+        loop {
+            match self.nomi.leggi_nome(n) {
+                Anon => break,
+                Str { pfx, .. } | Num { pfx, .. } => { parti.push(n); n = *pfx; }
+            }
+        }
+        if parti.is_empty() {
+            return write!(f, "[anonymous]")
+        }
+        for (i, p) in parti.iter().rev().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            match self.nomi.leggi_nome(*p) {
+                Str { value, .. } => scrivi_componente(f, value)?,
+                Num { value, .. } => write!(f, "{}", value)?,
+                Anon => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
+
+enum Componente<'s> {
+    Str(&'s str),
+    Num(u64),
+}
+
+// "Nat.add_comm", "«a.b».c", "x.1"; None se la stringa non è un nome ben formato
+fn componenti(s: &str) -> Option<Vec<Componente<'_>>> {
+    let mut out = Vec::new();
+    let mut resto = s;
+    loop {
+        let (parte, dopo) = match resto.strip_prefix('«') {
+            Some(r) => {
+                let fine = r.find('»')?;
+                (Componente::Str(&r[..fine]), &r[fine + '»'.len_utf8()..])
+            }
+            None => {
+                let fine = resto.find('.').unwrap_or(resto.len());
+                let p = &resto[..fine];
+                if p.is_empty() {
+                    return None
+                }
+                let c = match p.parse::<u64>() {
+                    Ok(v) if p.chars().all(|c| c.is_ascii_digit()) => Componente::Num(v),
+                    _ => Componente::Str(p),
+                };
+                (c, &resto[fine..])
+            }
+        };
+        out.push(parte);
+        if dopo.is_empty() {
+            return Some(out)
+        }
+        resto = dopo.strip_prefix('.')?;
+    }
+}
+
+impl<'a> Environment<'a> {
+    pub fn find_name(&self, s: &str) -> Option<NamePtr<'a>> {
+        let mut n: NamePtr<'a> = Ptr::from(0);
+        for c in componenti(s)? {
+            let nome = match c {
+                Componente::Str(value) => Str { pfx: n, value: value.to_string(), hash: hash64!(STR_HASH, n, value) },
+                Componente::Num(value) => Num { pfx: n, value, hash: hash64!(NUM_HASH, n, value) },
+            };
+            n = Ptr::from(self.names.get_index_of(&nome)?);
+        }
+        Some(n)
+    }
+
+    pub fn find_declar(&self, s: &str) -> Option<&Declar<'a>> {
+        self.declars.get(&self.find_name(s)?)
+    }
+}
@@ -3,49 +3,70 @@ use std::path::Path;
 use std::fs::OpenOptions;
 use std::io::{BufReader, BufRead};
 use std::slice::Iter;
-use std::sync::Mutex;
-use crate::declar::{ Declar::*, RecRulePtr};
-use crate::util::{ ExportFile, Ptr};
-use crate::hash64;
+use crate::declar::{ Declar, Declar::*, RecRulePtr};
+use crate::util::{ Environment, TypeChecker, Ptr, FxIndexMap, new_fx_index_map};
+use crate::{hash64, log};
 use crate::expr::ExprPtr;
 use crate::name::NamePtr;
 use crate::universe::{UparamsPtr, UniversePtr, PARAM_HASH, Universe::Param};
 
-static COUNTER: Mutex<u32> = Mutex::new(1);
-
-pub fn read_export_file<'a>(pathbuf: &Path) -> Result<ExportFile<'a>, Box<dyn Error>> {
+pub fn read_export_file<'a>(pathbuf: &Path) -> Result<Environment<'a>, Box<dyn Error>> {
     match OpenOptions::new().read(true).truncate(false).open(pathbuf) {
         Ok(file) => read_file(BufReader::new(file)), 
         Err(e) => panic!("Errore nell'apertura dell'export file: {:?}", e),
     }
 }
 
-pub fn read_file<'p, R: BufRead>( mut buf_reader: R, ) -> Result<ExportFile<'p>, Box<dyn Error>> {
-    let mut f = ExportFile::new();
+// i termini vengono creati da un type checker su un environment vuoto,
+// alla fine diventano l'environment restituito
+struct Parser<'e, 'p> {
+    tc: TypeChecker<'e, 'p>,
+    declars: FxIndexMap<NamePtr<'p>, Declar<'p>>,
+    declar_lines: FxIndexMap<NamePtr<'p>, u32>,
+    // riga dell'export file in lettura, per i messaggi del parser
+    line: u32,
+}
+
+pub fn read_file<'p, R: BufRead>( mut buf_reader: R, ) -> Result<Environment<'p>, Box<dyn Error>> {
+    let vuoto = Environment::new();
+    let mut f = Parser {
+        tc: TypeChecker::new(&vuoto),
+        declars: new_fx_index_map(),
+        declar_lines: new_fx_index_map(),
+        line: 1,
+    };
     let mut buffer = String::new();
     buf_reader.read_line(&mut buffer)?;
     if buffer.trim_end() != "2.0.0" {
         panic!("versione non valida ({:?}).", buffer.trim_end());
     }
     loop {
-        *COUNTER.lock().unwrap() += 1;
+        f.line += 1;
         buffer.clear();
         match buf_reader.read_line(&mut buffer)? {
             0 => break,
             _ => {
-                let linea : Vec<&str> = buffer.trim_end().split(' ').filter(|s| !s.is_empty()).collect();
+                let riga = buffer.strip_suffix('\n').unwrap_or(&buffer);
+                let riga = riga.strip_suffix('\r').unwrap_or(riga);
+                // la componente di un #NS può contenere spazi: è tutto il resto della riga, così com'è
+                if let Some((idx, resto)) = riga.split_once(' ')
+                    && let Some((pfx, componente)) = resto.strip_prefix("#NS ").and_then(|r| r.split_once(' ')) {
+                    f.parse_ns(idx.parse::<u32>()?, pfx, componente);
+                    continue
+                }
+                let linea : Vec<&str> = riga.trim_end().split(' ').filter(|s| !s.is_empty()).collect();
                 let mut iter: Iter<&str> = linea.iter();
-                let token = iter.next().ok_or_else(|| Box::<dyn Error>::from(format!("Non ci devono essere linee vuote :{}", *COUNTER.lock().unwrap())))?;
+                let token = iter.next().ok_or_else(|| Box::<dyn Error>::from(format!("Non ci devono essere linee vuote :{}", f.line)))?;
                 
                 match *token {
-                    "#AX" => f.parse_axiom(&mut iter),
-                    "#DEF" => f.parse_def(&mut iter),
-                    "#OPAQ" => f.parse_opaq(&mut iter),
-                    "#THM" => f.parse_theorem(&mut iter),
-                    "#QUOT" => f.parse_quot(&mut iter),
-                    "#IND" => f.parse_inductive(&mut iter),
-                    "#CTOR" => f.parse_constructor(&mut iter),
-                    "#REC" => f.parse_recursor(&mut iter),
+                    "#AX" => f.parse_axiom(&mut iter)?,
+                    "#DEF" => f.parse_def(&mut iter)?,
+                    "#OPAQ" => f.parse_opaq(&mut iter)?,
+                    "#THM" => f.parse_theorem(&mut iter)?,
+                    "#QUOT" => f.parse_quot(&mut iter)?,
+                    "#IND" => f.parse_inductive(&mut iter)?,
+                    "#CTOR" => f.parse_constructor(&mut iter)?,
+                    "#REC" => f.parse_recursor(&mut iter)?,
                     // otherwise, (Name, Universe, Expr)
                     idx => f.parse_altro(idx.parse::<u32>()?, &mut iter)?,
                 }
@@ -53,55 +74,55 @@ pub fn read_file<'p, R: BufRead>( mut buf_reader: R, ) -> Result<ExportFile<'p>,
             }
         }
     }
-    Ok(f)
+    Ok(f.tc.into_environment(f.declars, f.declar_lines))
 }
 
-impl<'a> ExportFile<'a>{
-    fn parse_axiom(&mut self, ws: &mut Iter<&str>) {
+impl<'e, 'a> Parser<'e, 'a> {
+    fn parse_axiom(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let uparams = self.parse_uparams(ws);
         let axiom = Axiom { name, ty, uparams };
-        self.declars.insert(name, axiom);
+        self.insert_declar(axiom)
     }
 
-    fn parse_def(&mut self, ws: &mut Iter<&str>) {
+    fn parse_def(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let val = self.parse_expr(ws);
         self.parse_hint(ws);
         let uparams = self.parse_uparams(ws);
         let definition = Definition { name, ty, uparams, val };
-        self.declars.insert(name, definition);
+        self.insert_declar(definition)
     }
 
-    fn parse_opaq(&mut self, ws: &mut Iter<&str>) {
+    fn parse_opaq(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let val = self.parse_expr(ws);
         let uparams = self.parse_uparams(ws);
         let opaq = Opaq { name, ty, uparams, val };
-        self.declars.insert(name, opaq);
+        self.insert_declar(opaq)
     }
 
-    fn parse_theorem(&mut self, ws: &mut Iter<&str>) {
+    fn parse_theorem(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let val = self.parse_expr(ws);
         let uparams = self.parse_uparams(ws);
         let theorem = Theorem { name, ty, uparams, val };
-        self.declars.insert(name, theorem);
+        self.insert_declar(theorem)
     }
 
-    fn parse_quot(&mut self, ws: &mut Iter<&str>) {
+    fn parse_quot(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let uparams = self.parse_uparams(ws);
         let quot = Quot { name, ty, uparams };
-        self.declars.insert(name, quot);
+        self.insert_declar(quot)
     }
     
-    fn parse_inductive(&mut self, ws: &mut Iter<&str>) {
+    fn parse_inductive(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         self.parse_bool(ws);
@@ -115,32 +136,31 @@ impl<'a> ExportFile<'a>{
         let all_ctor_names = self.parse_names(ws, num_ctors);
         let uparams = self.parse_uparams(ws);
         let inductive = Inductive{ name, ty, uparams, num_indices, all_ctor_names};
-        self.declars.insert(name, inductive);
-
+        self.insert_declar(inductive)
     }
 
-    fn parse_constructor(&mut self, ws: &mut Iter<&str>) {
+    fn parse_constructor(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let parent = self.parse_name(ws);
         assert!(self.declars.get(&parent).filter(|x| matches!(x, Inductive{..})).is_some(), 
-                "Inductive non esistente, riga:{}",*COUNTER.lock().unwrap());
+                "Inductive non esistente, riga:{}",self.line);
         self.parse_u32(ws);
         let num_params = self.parse_u32(ws);
         let num_fields = self.parse_u32(ws);
         let uparams = self.parse_uparams(ws);
         let ctor = Constructor{ name, ty, uparams, num_params, num_fields, parent };
-        self.declars.insert(name, ctor);
+        self.insert_declar(ctor)
     }
 
-    fn parse_recursor(&mut self, ws: &mut Iter<&str>) {
+    fn parse_recursor(&mut self, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let name = self.parse_name(ws);
         let ty = self.parse_expr(ws);
         let num_inductives = self.parse_u32(ws);
         let all_inductives = self.parse_names(ws, num_inductives);
         for i in 0..num_inductives as usize { 
             assert!(self.declars.get(&all_inductives[i]).filter(|x| matches!(x, Inductive{..})).is_some(), 
-                "Inductive non esistente, riga:{}",*COUNTER.lock().unwrap());
+                "Inductive non esistente, riga:{}",self.line);
         }
         self.parse_u32(ws);
         self.parse_u32(ws);
@@ -151,14 +171,30 @@ impl<'a> ExportFile<'a>{
         self.parse_bool(ws);
         let uparams = self.parse_uparams(ws);
         let recursor = Recursor{ name, ty, uparams, };
-        self.declars.insert(name, recursor);
+        self.insert_declar(recursor)
+    }
+
+    fn insert_declar(&mut self, declar: Declar<'a>) -> Result<(), Box<dyn Error>> {
+        let name = declar.name();
+        let linea = self.line;
+        if let Some(prima) = self.declar_lines.get(&name) {
+            return Err(Box::<dyn Error>::from(format!("Dichiarazione {} duplicata, righe {} e {}", self.tc.fmt_name(name), prima, linea)));
+        }
+        log!(Parser, Debug, "dichiarazione {} alla riga {}", self.tc.fmt_name(name), linea);
+        self.declar_lines.insert(name, linea);
+        self.declars.insert(name, declar);
+        Ok(())
     }
 
     fn parse_altro(&mut self, idx: u32, ws: &mut Iter<&str>) -> Result<(), Box<dyn Error>> {
         let oper = ws.next().expect("Parse primitive");
         match *oper {
             "#RR" => self.parse_rr(idx, ws),
-            "#NS" => self.parse_ns(idx, ws),
+            // #NS senza lo spazio prima della componente: la componente è vuota
+            "#NS" => {
+                let pfx = ws.next().ok_or_else(|| Box::<dyn Error>::from(format!("Riga {}: #NS senza prefisso", self.line)))?;
+                self.parse_ns(idx, pfx, "")
+            }
             "#NI" => self.parse_ni(idx, ws),
             "#US" => self.parse_us(idx, ws),
             "#UM" => self.parse_um(idx, ws),
@@ -174,7 +210,7 @@ impl<'a> ExportFile<'a>{
             "#EJ" => self.parse_ej(idx, ws),
             "#ELN" => self.parse_eln(idx, ws),
             "#ELS" => self.parse_els(idx, ws),
-            _ => return Err(Box::<dyn Error>::from(format!("Riga {} non riconosciuta", *COUNTER.lock().unwrap()))),
+            _ => return Err(Box::<dyn Error>::from(format!("Riga {} non riconosciuta", self.line))),
         }
         Ok(())
     }
@@ -182,11 +218,11 @@ impl<'a> ExportFile<'a>{
     fn parse_rr(&mut self, idx: u32, linea: &mut Iter<&str>) {
         let ctor_name = self.parse_name(linea);
         assert!(self.declars.get(&ctor_name).filter(|i| matches!(i, Constructor{..})).is_some(), 
-                "Constructor non esistente, riga:{}",*COUNTER.lock().unwrap());
+                "Constructor non esistente, riga:{}",self.line);
         let num_param = self.parse_u32(linea);
         let val = self.parse_expr(linea);
-        assert_eq!(idx as usize, self.rec_rules.len());
-        self.rec_rule(ctor_name, num_param, val);
+        assert_eq!(idx as usize, self.tc.num_rec_rules());
+        self.tc.rec_rule(ctor_name, num_param, val);
     }
     
     fn parse_rrs(&mut self, ws: &mut Iter<&str>, limit: u32) -> Vec<RecRulePtr<'a>> {
@@ -198,71 +234,70 @@ impl<'a> ExportFile<'a>{
         rr_ptrs
     }
 
-    fn parse_ns(&mut self, idx: u32, ws: &mut Iter<&str>) {
-        let pfx = self.parse_name(ws);
-        let sfx = self.parse_str(ws);
-        assert_eq!(idx as usize, self.names.len());
-        self.mk_str(pfx, sfx);
+    fn parse_ns(&mut self, idx: u32, pfx: &str, componente: &str) {
+        let pfx = self.parse_name(&mut [pfx].iter());
+        assert_eq!(idx as usize, self.tc.num_names());
+        self.tc.mk_str(pfx, componente.to_string());
     }
 
     fn parse_ni(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let pfx = self.parse_name(ws);
         let sfx = self.parse_u64(ws);
-        assert_eq!(idx as usize, self.names.len());
-        self.num(pfx, sfx);
+        assert_eq!(idx as usize, self.tc.num_names());
+        self.tc.num(pfx, sfx);
     }
     
     fn parse_us(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let l = self.parse_universe(ws);
-        assert_eq!(idx as usize, self.universes.len());
-        self.succ(l);
+        assert_eq!(idx as usize, self.tc.num_universes());
+        self.tc.succ(l);
     }
     
     fn parse_um(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let l = self.parse_universe(ws);
         let r = self.parse_universe(ws);
-        assert_eq!(idx as usize, self.universes.len());
-        self.max(l, r);
+        assert_eq!(idx as usize, self.tc.num_universes());
+        self.tc.max(l, r);
     }
 
     fn parse_uim(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let l = self.parse_universe(ws);
         let r = self.parse_universe(ws);
-        assert_eq!(idx as usize, self.universes.len());
-        self.imax(l,r);
+        assert_eq!(idx as usize, self.tc.num_universes());
+        self.tc.imax(l,r);
 
     }
 
     fn parse_up(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let n = self.parse_name(ws);
-        assert_eq!(idx as usize, self.universes.len());
-        self.param(n);
+        assert_eq!(idx as usize, self.tc.num_universes());
+        self.tc.param(n);
     }
 
     fn parse_ev(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let dbj_idx = self.parse_u32(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.var(dbj_idx);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.var(dbj_idx);
     }
 
     fn parse_es(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let universe = self.parse_universe(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.sort(universe);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.sort(universe);
     }
 
     fn parse_ec(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let name = self.parse_name(ws);
         let universes = self.parse_universes(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.mk_const(name, universes);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.mk_const(name, universes);
     }
 
     fn parse_ea(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let fun = self.parse_expr(ws);
         let arg = self.parse_expr(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.app(fun, arg);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.app(fun, arg);
     }
 
     fn parse_el(&mut self, idx: u32, ws: &mut Iter<&str>) {
@@ -270,8 +305,8 @@ impl<'a> ExportFile<'a>{
         let name = self.parse_name(ws);
         let tipo = self.parse_expr(ws);
         let body = self.parse_expr(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.lambda(name, tipo, body);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.lambda(name, tipo, body);
     }
 
     fn parse_ep(&mut self, idx: u32, ws: &mut Iter<&str>) {
@@ -279,8 +314,8 @@ impl<'a> ExportFile<'a>{
         let name = self.parse_name(ws);
         let tipo = self.parse_expr(ws);
         let body = self.parse_expr(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.pi(name, tipo, body);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.pi(name, tipo, body);
     }
 
     fn parse_ez(&mut self, idx: u32, ws: &mut Iter<&str>) {
@@ -288,40 +323,40 @@ impl<'a> ExportFile<'a>{
         let tipo = self.parse_expr(ws);
         let val = self.parse_expr(ws);
         let body = self.parse_expr(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.mk_let(name, tipo, val, body);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.mk_let(name, tipo, val, body);
     }
 
     fn parse_ej(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let name = self.parse_name(ws);
         let n_idx = self.parse_u32(ws);
         let structure = self.parse_expr(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.proj(name, n_idx, structure);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.proj(name, n_idx, structure);
     }
     
     fn parse_eln(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let ptr = self.parse_u128(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.nat_lit(ptr);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.nat_lit(ptr);
     }
 
     fn parse_els(&mut self, idx: u32, ws: &mut Iter<&str>) {
         let ptr = self.parse_hex_string(ws);
-        assert_eq!(idx as usize, self.exprs.len());
-        self.str_lit(ptr);
+        assert_eq!(idx as usize, self.tc.num_exprs());
+        self.tc.str_lit(ptr);
     }
 
 
     fn parse_rec_rule(&mut self, ws: &mut Iter<&str>) -> RecRulePtr<'a> {
         let idx = self.parse_u32(ws);
-        assert!(self.rec_rules.get_index(idx as usize).is_some());
+        assert!((idx as usize) < self.tc.num_rec_rules());
         Ptr::from(idx as usize)
     }
 
     fn parse_name(&mut self, ws: &mut Iter<&str>) -> NamePtr<'a> {
         let idx = self.parse_u32(ws);
-        assert!(self.names.get_index(idx as usize).is_some());
+        assert!((idx as usize) < self.tc.num_names());
         Ptr::from(idx as usize)
     }
     
@@ -336,7 +371,7 @@ impl<'a> ExportFile<'a>{
     
     fn parse_universe(&mut self, ws: &mut Iter<&str>) -> UniversePtr<'a> {
         let idx = self.parse_u32(ws);
-        assert!(self.universes.get_index(idx as usize).is_some());
+        assert!((idx as usize) < self.tc.num_universes());
         Ptr::from(idx as usize)
     }
     
@@ -346,58 +381,53 @@ impl<'a> ExportFile<'a>{
             let x = self.parse_universe(ws);
             universes.push(x)
         }
-        self.alloc_uparams(universes)    
+        self.tc.alloc_uparams(universes)    
     }
 
     fn parse_expr(&mut self, ws: &mut Iter<&str>) -> ExprPtr<'a> {
         let idx = self.parse_u32(ws);
-        assert!(self.exprs.get_index(idx as usize).is_some());
+        assert!((idx as usize) < self.tc.num_exprs());
         Ptr::from(idx as usize)
     }
     
     fn parse_u32(&mut self, ws: &mut Iter<&str>) -> u32 {
-        ws.next().expect(&format!("Valore non trovato, linea {}",*COUNTER.lock().unwrap())).parse::<u32>().expect(&format!("Valore diverso da un u32, linea {}", *COUNTER.lock().unwrap()))
+        ws.next().unwrap_or_else(|| panic!("Valore non trovato, linea {}", self.line)).parse::<u32>().unwrap_or_else(|_| panic!("Valore diverso da un u32, linea {}", self.line))
     }
     
     fn parse_u128(&mut self, ws: &mut Iter<&str>) -> u128 {
-        ws.next().expect(&format!("Valore non trovato, linea {}",*COUNTER.lock().unwrap())).parse::<u128>().expect(&format!("Valore diverso da un u64, linea {}", *COUNTER.lock().unwrap()))
+        ws.next().unwrap_or_else(|| panic!("Valore non trovato, linea {}", self.line)).parse::<u128>().unwrap_or_else(|_| panic!("Valore diverso da un u128, linea {}", self.line))
     }
     
     fn parse_u64(&mut self, ws: &mut Iter<&str>) -> u64 {
-        ws.next().expect(&format!("Valore non trovato, linea {}",*COUNTER.lock().unwrap())).parse::<u64>().expect(&format!("Valore diverso da un u64, linea {}", *COUNTER.lock().unwrap()))
+        ws.next().unwrap_or_else(|| panic!("Valore non trovato, linea {}", self.line)).parse::<u64>().unwrap_or_else(|_| panic!("Valore diverso da un u64, linea {}", self.line))
     }
     
     fn parse_bool(&mut self, ws: &mut Iter<&str>) -> bool {
-        let s = ws.next().expect(&format!("valore non trovato, linea {}", *COUNTER.lock().unwrap()));
+        let s = ws.next().unwrap_or_else(|| panic!("valore non trovato, linea {}", self.line));
         match *s{
             "0" => false,
             "1" => true,
-            owise => panic!("Manca un valore (0 | 1 ), trovato {:?} linea = {}",owise, *COUNTER.lock().unwrap()),
+            owise => panic!("Manca un valore (0 | 1 ), trovato {:?} linea = {}",owise, self.line),
         }
     }
 
     fn parse_info(&mut self, ws: &mut Iter<&str>) {
-        let s = ws.next().expect(&format!("Manca un valore (#BD | #BI | #BC | #BS), linea {}", *COUNTER.lock().unwrap()));
+        let s = ws.next().unwrap_or_else(|| panic!("Manca un valore (#BD | #BI | #BC | #BS), linea {}", self.line));
         match *s {
             "#BD" | "#BI" | "#BC" | "#BS" => {}
-            _ => panic!("valore {:?} non valido in linea {}", s, *COUNTER.lock().unwrap()),
+            _ => panic!("valore {:?} non valido in linea {}", s, self.line),
         }
     }
 
     fn parse_hint(&mut self, ws: &mut Iter<&str>)  {
-        let h = ws.next().expect(&format!("Valore non trovato, linea {}",*COUNTER.lock().unwrap()));
+        let h = ws.next().unwrap_or_else(|| panic!("Valore non trovato, linea {}", self.line));
         match *h {
             "O" | "A" => {}
             "R" => { self.parse_u32(ws); }
-             owise => panic!("Manca un valore (O | A | R), trovato {:?} linea = {}", owise, *COUNTER.lock().unwrap()),
+             owise => panic!("Manca un valore (O | A | R), trovato {:?} linea = {}", owise, self.line),
         }
     }
 
-    fn parse_str(&mut self, ws: &mut Iter<&str>) -> String {
-        let s = ws.next().unwrap();
-        s.to_string()
-    }
-
     pub fn parse_hex_string(&mut self, ws: &mut Iter<&str>) -> String {
         ws.map(|hex| { u8::from_str_radix(hex, 16).unwrap() as char }).collect()
     }
@@ -406,9 +436,10 @@ impl<'a> ExportFile<'a>{
         let name = self.parse_name(ws);
         let hash = hash64!(PARAM_HASH, name);
         // Has to already exist
-        let val = Param {name , hash };
-        let idx = self.universes.get_index_of(&val).unwrap();
-        Ptr::from(idx as usize)
+        let esistenti = self.tc.num_universes();
+        let ptr = self.tc.alloc_universe(Param {name , hash });
+        assert!(ptr.idx < esistenti, "Parametro universale non dichiarato, linea {}", self.line);
+        ptr
     }
 
     fn parse_uparams(&mut self, ws: &mut Iter<&str>) -> UparamsPtr<'a> {
@@ -416,10 +447,40 @@ impl<'a> ExportFile<'a>{
         while ws.len() > 0 {
             let universe_ptr = self.parse_param(ws);
             if universes.contains(&universe_ptr){
-                panic!("Parametro universale già inserito, linea {}", *COUNTER.lock().unwrap());
+                panic!("Parametro universale già inserito, linea {}", self.line);
             }
             universes.push(universe_ptr)
         }
-        self.alloc_uparams(universes)
+        self.tc.alloc_uparams(universes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name::Name;
+
+    // pfx e componente di ogni nome, a partire dall'indice 1
+    fn componenti(export: &str) -> Vec<(usize, String)> {
+        let env = read_file(export.as_bytes()).unwrap();
+        env.names.iter().skip(1).map(|n| match n {
+            Name::Str { pfx, value, .. } => (pfx.idx, value.clone()),
+            Name::Num { pfx, value, .. } => (pfx.idx, value.to_string()),
+            Name::Anon => panic!("nome anonimo dopo l'indice 0"),
+        }).collect()
+    }
+
+    #[test]
+    fn ns_con_componente_verbatim() {
+        let export = "2.0.0\n1 #NS 0 a b\n2 #NS 1 \n3 #NS 2\n4 #NS 0 «c»\n5 #NS 0  d\n6 #NS 0 e\r\n7 #NI 6 3  \n";
+        assert_eq!(componenti(export), vec![
+            (0, "a b".to_string()),
+            (1, String::new()),
+            (2, String::new()),
+            (0, "«c»".to_string()),
+            (0, " d".to_string()),
+            (0, "e".to_string()),
+            (6, "3".to_string()),
+        ]);
     }
 }
@@ -0,0 +1,247 @@
+use crate::expr::{Expr::*, ExprPtr};
+use crate::name::{LeggiNomi, Name, NamePtr};
+use crate::universe::{Universe, UniversePtr};
+use crate::util::{TypeChecker, FxIndexSet, new_fx_index_set};
+
+// colonne disponibili prima di andare a capo
+const LARGHEZZA: usize = 100;
+// oltre questo numero di nodi il resto del termine diventa "…"
+const MAX_NODI: usize = 2000;
+
+// Un gruppo viene stampato su una riga se ci sta, altrimenti ogni parte dopo la prima
+// va a capo con `rientro` spazi in più.
+enum Doc {
+    Testo(String),
+    Concat(Vec<Doc>),
+    Gruppo { parti: Vec<Doc>, rientro: usize },
+}
+
+impl Doc {
+    fn testo(s: impl Into<String>) -> Doc {
+        Doc::Testo(s.into())
+    }
+
+    fn parentesi(self) -> Doc {
+        Doc::Concat(vec![Doc::testo("("), self, Doc::testo(")")])
+    }
+
+    fn larghezza(&self) -> usize {
+        match self {
+            Doc::Testo(s) => s.chars().count(),
+            Doc::Concat(parti) => parti.iter().map(Doc::larghezza).sum(),
+            Doc::Gruppo { parti, .. } => parti.iter().map(Doc::larghezza).sum::<usize>() + parti.len().saturating_sub(1),
+        }
+    }
+
+    fn piatto(&self, out: &mut String) {
+        match self {
+            Doc::Testo(s) => out.push_str(s),
+            Doc::Concat(parti) => parti.iter().for_each(|p| p.piatto(out)),
+            Doc::Gruppo { parti, .. } => {
+                for (i, p) in parti.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    p.piatto(out);
+                }
+            }
+        }
+    }
+
+    // restituisce la colonna a fine stampa
+    fn stampa(&self, out: &mut String, rientro: usize, colonna: usize) -> usize {
+        let w = self.larghezza();
+        if colonna + w <= LARGHEZZA {
+            self.piatto(out);
+            return colonna + w
+        }
+        match self {
+            Doc::Testo(s) => {
+                out.push_str(s);
+                colonna + w
+            }
+            Doc::Concat(parti) => parti.iter().fold(colonna, |c, p| p.stampa(out, rientro, c)),
+            Doc::Gruppo { parti, rientro: r } => {
+                let mut c = colonna;
+                for (i, p) in parti.iter().enumerate() {
+                    if i > 0 {
+                        out.push('\n');
+                        out.extend(std::iter::repeat_n(' ', rientro + r));
+                        c = rientro + r;
+                    }
+                    c = p.stampa(out, rientro + r, c);
+                }
+                c
+            }
+        }
+    }
+}
+
+struct Stampa<'a, 'e, 't> {
+    tc: &'a TypeChecker<'e, 't>,
+    // nomi dei binder attraversati, l'ultimo è la Var 0
+    nomi: Vec<String>,
+    nodi: usize,
+}
+
+impl<'a, 'e, 't> Stampa<'a, 'e, 't> {
+    fn nome_libero(&self, nome: NamePtr<'t>) -> String {
+        let base = match self.tc.read_name(nome) {
+            Name::Anon => "x".to_string(),
+            _ => self.tc.fmt_name(nome),
+        };
+        let mut out = base.clone();
+        let mut i = 1;
+        while self.nomi.contains(&out) {
+            out = format!("{}_{}", base, i);
+            i += 1;
+        }
+        out
+    }
+
+    fn atomo(&self, e: ExprPtr<'t>) -> bool {
+        match self.tc.read_expr(e) {
+            Var { .. } | FreeVar { .. } | Const { .. } | NatLit { .. } | StrLit { .. } | Proj { .. } => true,
+            Sort { universe, .. } => !self.sort(universe).contains(' '),
+            _ => false,
+        }
+    }
+
+    fn argomento(&mut self, e: ExprPtr<'t>) -> Doc {
+        let d = self.doc(e);
+        if self.atomo(e) || self.nodi > MAX_NODI { d } else { d.parentesi() }
+    }
+
+    fn sort(&self, u: UniversePtr<'t>) -> String {
+        match self.tc.read_universe(u) {
+            Universe::Zero => "Prop".to_string(),
+            Universe::Succ { pred, .. } if pred == self.tc.zero() => "Type".to_string(),
+            Universe::Succ { pred, .. } => format!("Type {}", self.tc.fmt_argomento(pred)),
+            _ => format!("Sort {}", self.tc.fmt_argomento(u)),
+        }
+    }
+
+    fn doc(&mut self, e: ExprPtr<'t>) -> Doc {
+        self.nodi += 1;
+        if self.nodi > MAX_NODI {
+            return Doc::testo("…")
+        }
+        match self.tc.read_expr(e) {
+            Var { dbj_idx, .. } => match self.nomi.len().checked_sub(dbj_idx as usize + 1) {
+                Some(i) => Doc::testo(self.nomi[i].clone()),
+                None => Doc::testo(format!("#{}", dbj_idx)),
+            },
+            FreeVar { idx, .. } => Doc::testo(format!("_x{}", idx)),
+            Sort { universe, .. } => Doc::testo(self.sort(universe)),
+            Const { name, universes, .. } => {
+                let us = self.tc.read_uparams(universes);
+                if us.is_empty() {
+                    Doc::testo(self.tc.fmt_name(name))
+                } else {
+                    let us = us.iter().map(|&u| self.tc.fmt_universe(u)).collect::<Vec<_>>().join(", ");
+                    Doc::testo(format!("{}.{{{}}}", self.tc.fmt_name(name), us))
+                }
+            }
+            App { .. } => {
+                let mut args = Vec::new();
+                let mut f = e;
+                while let App { fun, arg, .. } = self.tc.read_expr(f) {
+                    args.push(arg);
+                    f = fun;
+                }
+                let mut parti = vec![self.argomento(f)];
+                for &a in args.iter().rev() {
+                    parti.push(self.argomento(a));
+                }
+                Doc::Gruppo { parti, rientro: 2 }
+            }
+            Lambda { .. } => {
+                let mut binder = vec![Doc::testo("fun")];
+                let mut b = e;
+                let n = self.nomi.len();
+                while let Lambda { name, ty, body, .. } = self.tc.read_expr(b) {
+                    let t = self.doc(ty);
+                    let x = self.nome_libero(name);
+                    binder.push(Doc::Concat(vec![Doc::testo(format!("({} : ", x)), t, Doc::testo(")")]));
+                    self.nomi.push(x);
+                    b = body;
+                }
+                binder.push(Doc::testo("=>"));
+                let corpo = self.doc(b);
+                self.nomi.truncate(n);
+                Doc::Gruppo { parti: vec![Doc::Gruppo { parti: binder, rientro: 4 }, corpo], rientro: 2 }
+            }
+            Pi { name, ty, body, .. } => {
+                let t = self.doc(ty);
+                let dominio = if self.tc.usa_var(body, 0) {
+                    let x = self.nome_libero(name);
+                    self.nomi.push(x.clone());
+                    Doc::Concat(vec![Doc::testo(format!("({} : ", x)), t, Doc::testo(") →")])
+                } else {
+                    // il nome non è visibile, ma la Var 0 del corpo deve restare allineata
+                    self.nomi.push("_".to_string());
+                    let t = if matches!(self.tc.read_expr(ty), Pi { .. } | Lambda { .. } | Let { .. }) { t.parentesi() } else { t };
+                    Doc::Concat(vec![t, Doc::testo(" →")])
+                };
+                let corpo = self.doc(body);
+                self.nomi.pop();
+                Doc::Gruppo { parti: vec![dominio, corpo], rientro: 0 }
+            }
+            Let { name, ty, val, body, .. } => {
+                let t = self.doc(ty);
+                let v = self.doc(val);
+                let x = self.nome_libero(name);
+                let testa = Doc::Gruppo {
+                    parti: vec![Doc::Concat(vec![Doc::testo(format!("let {} : ", x)), t, Doc::testo(" :=")]), Doc::Concat(vec![v, Doc::testo(";")])],
+                    rientro: 4,
+                };
+                self.nomi.push(x);
+                let corpo = self.doc(body);
+                self.nomi.pop();
+                Doc::Gruppo { parti: vec![testa, corpo], rientro: 0 }
+            }
+            Proj { idx, structure, .. } => Doc::Concat(vec![self.argomento(structure), Doc::testo(format!(".{}", idx + 1))]),
+            NatLit { val, .. } => Doc::testo(val.to_string()),
+            StrLit { val, .. } => Doc::testo(format!("{:?}", val)),
+        }
+    }
+}
+
+impl<'e, 't> TypeChecker<'e, 't> {
+    pub fn fmt_name(&self, name: NamePtr<'t>) -> String {
+        self.display_name(name).to_string()
+    }
+
+    pub fn fmt_expr(&self, e: ExprPtr<'t>) -> String {
+        self.fmt_expr_rientro(e, 0)
+    }
+
+    // come fmt_expr, con le righe successive alla prima rientrate di `rientro` spazi
+    pub fn fmt_expr_rientro(&self, e: ExprPtr<'t>, rientro: usize) -> String {
+        let mut s = Stampa { tc: self, nomi: Vec::new(), nodi: 0 };
+        let doc = s.doc(e);
+        let mut out = String::new();
+        doc.stampa(&mut out, rientro, rientro);
+        out
+    }
+
+    // la Var con indice `dbj` (rispetto alla radice di e) compare in e
+    pub fn usa_var(&self, e: ExprPtr<'t>, dbj: u32) -> bool {
+        let mut visti: FxIndexSet<(ExprPtr<'t>, u32)> = new_fx_index_set();
+        let mut pila = vec![(e, dbj)];
+        while let Some((e, d)) = pila.pop() {
+            if self.expr_info(e).loose_bvar_range <= d || !visti.insert((e, d)) {
+                continue
+            }
+            match self.read_expr(e) {
+                Var { dbj_idx, .. } if dbj_idx == d => return true,
+                App { fun, arg, .. } => pila.extend([(fun, d), (arg, d)]),
+                Lambda { ty, body, .. } | Pi { ty, body, .. } => pila.extend([(ty, d), (body, d + 1)]),
+                Let { ty, val, body, .. } => pila.extend([(ty, d), (val, d), (body, d + 1)]),
+                Proj { structure, .. } => pila.push((structure, d)),
+                _ => {}
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+use std::time::Instant;
+use crate::report::json_str;
+use crate::util::TypeChecker;
+
+// impostazioni comuni ai type checker di una stessa traccia
+#[derive(Debug, Clone, Copy)]
+pub struct Traccia {
+    // profondità massima degli span registrati
+    pub profondita: u32,
+    // origine dei tempi degli eventi
+    pub inizio: Instant,
+}
+
+impl Traccia {
+    pub fn nuova(profondita: u32) -> Traccia {
+        Traccia { profondita: profondita.max(1), inizio: Instant::now() }
+    }
+}
+
+// evento completo ("ph": "X") del formato trace-event di Chrome/Perfetto
+struct Evento {
+    nome: String,
+    inizio: f64,
+    durata: f64,
+    args: String,
+}
+
+// eventi di un type checker, scritti da scrivi_traccia
+pub struct Profilo {
+    eventi: Vec<Evento>,
+    profondita: u32,
+    traccia: Traccia,
+    tid: u32,
+}
+
+impl Profilo {
+    pub fn nuovo(traccia: Traccia, tid: u32) -> Profilo {
+        Profilo { eventi: Vec::new(), profondita: 0, traccia, tid }
+    }
+}
+
+// inizio di uno span, None se non va registrato
+pub type Span = Option<Instant>;
+
+impl<'e, 't> TypeChecker<'e, 't> {
+    pub fn apri_span(&mut self) -> Span {
+        let p = self.profilo.as_mut()?;
+        p.profondita += 1;
+        (p.profondita <= p.traccia.profondita).then(Instant::now)
+    }
+
+    // args è il contenuto di un oggetto JSON, per esempio "\"e\": 12"
+    pub fn chiudi_span(&mut self, span: Span, nome: impl FnOnce() -> String, args: impl FnOnce() -> String) {
+        let Some(p) = self.profilo.as_mut() else { return };
+        p.profondita -= 1;
+        if let Some(inizio) = span {
+            let fine = Instant::now();
+            p.eventi.push(Evento {
+                nome: nome(),
+                inizio: inizio.duration_since(p.traccia.inizio).as_secs_f64() * 1e6,
+                durata: fine.duration_since(inizio).as_secs_f64() * 1e6,
+                args: args(),
+            });
+        }
+    }
+}
+
+pub fn scrivi_traccia<W: Write>(out: &mut W, profili: &[Profilo]) -> io::Result<()> {
+    writeln!(out, "{{\"displayTimeUnit\": \"ms\", \"traceEvents\": [")?;
+    let eventi = profili.iter().flat_map(|p| p.eventi.iter().map(move |e| (p.tid, e))).collect::<Vec<_>>();
+    for (i, (tid, e)) in eventi.iter().enumerate() {
+        let virgola = if i + 1 < eventi.len() { "," } else { "" };
+        writeln!(out, "{{\"name\": {}, \"ph\": \"X\", \"ts\": {:.3}, \"dur\": {:.3}, \"pid\": 1, \"tid\": {}, \"args\": {{{}}}}}{}",
+            json_str(&e.nome), e.inizio, e.durata, tid, e.args, virgola)?;
+    }
+    writeln!(out, "]}}")
+}
@@ -0,0 +1,67 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use crate::name::LeggiNomi;
+use crate::tc::Risultato;
+use crate::util::Environment;
+
+// stringa JSON con le sequenze di escape obbligatorie
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// Una voce per ogni dichiarazione dell'export file, nell'ordine del file; quelle senza
+// risultato sono "skipped". assiomi è il risultato di Environment::assiomi.
+pub fn scrivi_json<W: Write>(env: &Environment, risultati: &[Risultato], assiomi: &[Vec<usize>], out: &mut W) -> io::Result<()> {
+    let mut per_indice = vec![None; env.declars.len()];
+    for r in risultati {
+        per_indice[r.indice] = Some(r);
+    }
+    let (mut ok, mut errori, mut saltate) = (0, 0, 0);
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"declarations\": [")?;
+    for (i, d) in env.declars.values().enumerate() {
+        let mut voce = format!("{{\"name\": {}, \"kind\": {}", json_str(&env.display_name(d.name()).to_string()), json_str(d.kind()));
+        match per_indice[i] {
+            None => {
+                saltate += 1;
+                voce += ", \"status\": \"skipped\"";
+            }
+            Some(r) => {
+                match &r.errore {
+                    None => {
+                        ok += 1;
+                        voce += ", \"status\": \"ok\"";
+                    }
+                    Some((tipo, messaggio)) => {
+                        errori += 1;
+                        let _ = write!(voce, ", \"status\": \"error\", \"error_kind\": {}, \"message\": {}", json_str(tipo), json_str(messaggio));
+                    }
+                }
+                let _ = write!(voce, ", \"time_ms\": {:.3}", r.tempo.as_secs_f64() * 1000.0);
+            }
+        }
+        let nomi = assiomi[i].iter()
+            .map(|&a| json_str(&env.display_name(env.declars[a].name()).to_string()))
+            .collect::<Vec<_>>();
+        let _ = write!(voce, ", \"axioms\": [{}]}}", nomi.join(", "));
+        let virgola = if i + 1 < env.declars.len() { "," } else { "" };
+        writeln!(out, "    {}{}", voce, virgola)?;
+    }
+    writeln!(out, "  ],")?;
+    writeln!(out, "  \"summary\": {{\"ok\": {}, \"error\": {}, \"skipped\": {}}}", ok, errori, saltate)?;
+    writeln!(out, "}}")
+}
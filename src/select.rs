@@ -0,0 +1,58 @@
+use crate::name::{Name, NamePtr};
+use crate::util::Environment;
+
+// Dichiarazioni da controllare, scelte per nome o per prefisso.
+// Senza nomi né prefissi sono selezionate tutte.
+pub struct Selezione<'a> {
+    pub nomi: Vec<NamePtr<'a>>,
+    pub prefissi: Vec<NamePtr<'a>>,
+    pub esclusi: Vec<NamePtr<'a>>,
+}
+
+impl<'a> Selezione<'a> {
+    pub fn new(env: &Environment<'a>, nomi: &[String], prefissi: &[String], esclusi: &[String]) -> Result<Self, String> {
+        let trova = |s: &String| env.find_name(s).ok_or_else(|| format!("nome {} non trovato nell'export file", s));
+        let nomi = nomi.iter().map(|s| {
+            let n = trova(s)?;
+            match env.declars.contains_key(&n) {
+                true => Ok(n),
+                false => Err(format!("{} non è una dichiarazione", s)),
+            }
+        }).collect::<Result<Vec<_>, _>>()?;
+        let prefissi = prefissi.iter().map(trova).collect::<Result<Vec<_>, _>>()?;
+        let esclusi = esclusi.iter().map(trova).collect::<Result<Vec<_>, _>>()?;
+        Ok(Selezione { nomi, prefissi, esclusi })
+    }
+
+    // indici in env.declars delle dichiarazioni selezionate, in ordine
+    pub fn indici(&self, env: &Environment<'a>) -> Vec<usize> {
+        let tutte = self.nomi.is_empty() && self.prefissi.is_empty();
+        env.declars.keys().enumerate()
+            .filter(|&(_, &n)| tutte || self.nomi.contains(&n) || self.prefissi.iter().any(|&p| ha_prefisso(env, n, p)))
+            .filter(|&(_, &n)| !self.esclusi.iter().any(|&p| ha_prefisso(env, n, p)))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+// p è n o uno dei suoi prefissi, componente per componente
+pub fn ha_prefisso<'a>(env: &Environment<'a>, mut n: NamePtr<'a>, p: NamePtr<'a>) -> bool {
+    loop {
+        if n == p {
+            return true
+        }
+        match env.read_name(n) {
+            Name::Anon => return false,
+            Name::Str { pfx, .. } | Name::Num { pfx, .. } => n = pfx,
+        }
+    }
+}
+
+// un nome per riga; righe vuote e commenti (#) ignorati
+pub fn leggi_nomi(testo: &str) -> Vec<String> {
+    testo.lines()
+        .map(str::trim)
+        .filter(|r| !r.is_empty() && !r.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
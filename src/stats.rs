@@ -0,0 +1,62 @@
+use crate::name::LeggiNomi;
+use crate::tc::Risultato;
+use crate::util::Environment;
+
+// contatori del type checker, sempre attivi
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Statistiche {
+    pub infer: u64,
+    pub infer_cache: u64,
+    pub whnf: u64,
+    pub whnf_cache: u64,
+    pub def_eq: u64,
+    pub def_eq_cache: u64,
+}
+
+impl Statistiche {
+    // contatori accumulati da `prima` in poi
+    pub fn da(&self, prima: &Statistiche) -> Statistiche {
+        Statistiche {
+            infer: self.infer - prima.infer,
+            infer_cache: self.infer_cache - prima.infer_cache,
+            whnf: self.whnf - prima.whnf,
+            whnf_cache: self.whnf_cache - prima.whnf_cache,
+            def_eq: self.def_eq - prima.def_eq,
+            def_eq_cache: self.def_eq_cache - prima.def_eq_cache,
+        }
+    }
+
+    pub fn somma(&mut self, altre: &Statistiche) {
+        self.infer += altre.infer;
+        self.infer_cache += altre.infer_cache;
+        self.whnf += altre.whnf;
+        self.whnf_cache += altre.whnf_cache;
+        self.def_eq += altre.def_eq;
+        self.def_eq_cache += altre.def_eq_cache;
+    }
+}
+
+fn percentuale(parte: u64, totale: u64) -> f64 {
+    if totale == 0 { 0.0 } else { 100.0 * parte as f64 / totale as f64 }
+}
+
+pub fn stampa_statistiche(env: &Environment, risultati: &[Risultato], n: usize) {
+    let mut totale = Statistiche::default();
+    for r in risultati {
+        totale.somma(&r.statistiche);
+    }
+    let tempo: f64 = risultati.iter().map(|r| r.tempo.as_secs_f64()).sum();
+    println!("\nStatistiche: {} dichiarazioni controllate in {:.3} ms", risultati.len(), tempo * 1000.0);
+    println!("  infer:  {} chiamate, {:.1}% dalla cache", totale.infer, percentuale(totale.infer_cache, totale.infer));
+    println!("  whnf:   {} chiamate, {:.1}% dalla cache", totale.whnf, percentuale(totale.whnf_cache, totale.whnf));
+    println!("  def_eq: {} chiamate, {:.1}% dalla cache", totale.def_eq, percentuale(totale.def_eq_cache, totale.def_eq));
+
+    let mut lente = risultati.iter().collect::<Vec<_>>();
+    lente.sort_by_key(|r| std::cmp::Reverse(r.tempo));
+    println!("Le {} dichiarazioni più lente:", n.min(lente.len()));
+    for r in lente.into_iter().take(n) {
+        let s = &r.statistiche;
+        println!("  {:>10.3} ms  {}  (infer {}, whnf {}, def_eq {})", r.tempo.as_secs_f64() * 1000.0,
+            env.display_name(env.declars[r.indice].name()), s.infer, s.whnf, s.def_eq);
+    }
+}
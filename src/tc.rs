@@ -2,78 +2,203 @@ use crate::declar::Declar::*;
 use crate::name::NamePtr;
 use crate::expr::{Expr::*, ExprPtr};
 use crate::universe::{UniversePtr, UparamsPtr};
-use crate::util::ExportFile;
+use crate::error::{TcError, TcError::*};
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::log;
+use crate::profile::{Profilo, Traccia};
+use crate::stats::Statistiche;
+use crate::util::{Environment, TypeChecker, new_fx_index_set};
 
-impl<'t> ExportFile<'t> {
+impl<'e, 't> TypeChecker<'e, 't> {
     
-    pub fn check_all_declars(&mut self) { 
-        for d in self.declars.clone().into_values() {
-            self.check_info(d.uparams(), d.ty(), d.val(), d.name());
+    pub fn check_all_declars(&mut self) -> Vec<Risultato> {
+        let tutte = (0..self.env.declars.len()).collect::<Vec<_>>();
+        self.check_declars(&tutte)
+    }
+
+    // controlla solo le dichiarazioni con questi indici, le altre sono considerate corrette
+    pub fn check_declars(&mut self, indici: &[usize]) -> Vec<Risultato> {
+        let mut out = Vec::new();
+        for &i in indici {
+            let r = self.check_declar(i);
+            if let Some((_, messaggio)) = &r.errore {
+                println!("{}", messaggio);
+            }
+            out.push(r);
         }
-        println!("\nNessun errore nelle dichiarazioni");
+        riepilogo(&out);
+        out
     }
 
-    pub fn check_info(&mut self, uparams: UparamsPtr<'t>, ty: ExprPtr<'t>, val: Option<ExprPtr<'t>>, name: NamePtr<'t>) {
-        
-        let em = self.read_uparams(uparams);
-        if !em.is_empty() {
-            self.controllo_parametri(ty, uparams);
+    pub fn check_declar(&mut self, indice: usize) -> Risultato {
+        let d = &self.env.declars[indice];
+        log!(Infer, Info, "dichiarazione {}", self.fmt_name(d.name()));
+        let inizio = Instant::now();
+        let prima = self.statistiche;
+        let span = self.apri_span();
+        let errore = self.check_info(d.uparams(), d.ty(), d.val(), d.name())
+            .err()
+            .map(|e| (e.kind(), self.fmt_error(&e)));
+        let nome = span.map(|_| self.fmt_name(d.name())).unwrap_or_default();
+        self.chiudi_span(span, || nome, || format!("\"errore\": {}", errore.is_some()));
+        Risultato { indice, errore, tempo: inizio.elapsed(), statistiche: self.statistiche.da(&prima) }
+    }
+
+    pub fn check_info(&mut self, uparams: UparamsPtr<'t>, ty: ExprPtr<'t>, val: Option<ExprPtr<'t>>, name: NamePtr<'t>) -> Result<(), TcError<'t>> {
+        self.passi = 0;
+        self.scadenza = self.limiti.tempo.map(|t| Instant::now() + t);
+        self.declar_corrente = Some(name);
+        self.ricorsione = 0;
+        self.controllo_costanti(name, ty, val)?;
+        self.controllo_chiuso(name, ty, false)?;
+        if let Some(val) = val {
+            self.controllo_chiuso(name, val, true)?;
+        }
+        self.controllo_profondita(ty)?;
+        if let Some(val) = val {
+            self.controllo_profondita(val)?;
         }
-        self.is_sort(ty);
-        if val.is_some() {
-            let val = val.unwrap();
-            if !em.is_empty() {
-                self.controllo_parametri(val, uparams);
+        self.controllo_parametri(name, ty, uparams)?;
+        self.is_sort(ty)?;
+        if let Some(val) = val {
+            self.controllo_parametri(name, val, uparams)?;
+            let v = self.infer(val)?;
+
+            if !self.def_eq(ty, v)?{
+                let universi = self.spiega_fallimento();
+                return Err(TypeMismatch { declar: name, ty, val_ty: v, universi })
             }
-            let v = self.infer(val);
+        }
+        Ok(())
+    }
+
+    pub fn controllo_chiuso(&self, name: NamePtr<'t>, e: ExprPtr<'t>, in_val: bool) -> Result<(), TcError<'t>> {
+        match self.loose_bvar_range(e) {
+            0 => Ok(()),
+            range => Err(LooseBoundVar { declar: name, in_val, range }),
+        }
+    }
 
-            if !self.def_eq(ty, v){
-                println!("Errore nella dichiarazione {:?}: tipo della definizione non coincide. ty={:?}, val_type={:?}", name.idx, self.read_expr(ty), self.read_expr(v));
+    // ogni costante usata deve essere dichiarata prima della dichiarazione name
+    pub fn controllo_costanti(&self, name: NamePtr<'t>, ty: ExprPtr<'t>, val: Option<ExprPtr<'t>>) -> Result<(), TcError<'t>> {
+        let pos = self.env.declars.get_index_of(&name);
+        let mut usate = new_fx_index_set();
+        self.costanti(ty, &mut usate);
+        if let Some(val) = val {
+            self.costanti(val, &mut usate);
+        }
+        for costante in usate {
+            match (self.env.declars.get_index_of(&costante), pos) {
+                (None, _) => return Err(UndefinedConstant { declar: name, costante }),
+                (Some(i), Some(p)) if i == p => return Err(SelfReference { declar: name }),
+                (Some(i), Some(p)) if i > p => return Err(ForwardReference { declar: name, costante }),
+                _ => {}
             }
         }
+        Ok(())
+    }
 
+    // consuma un passo del budget della dichiarazione in controllo
+    fn passo(&mut self) -> Result<(), TcError<'t>> {
+        self.passi += 1;
+        let esauriti = self.limiti.passi.is_some_and(|max| self.passi > max);
+        // l'orologio si legge solo ogni 1024 passi
+        let tempo = self.passi.is_multiple_of(1024) && self.scadenza.is_some_and(|s| Instant::now() >= s);
+        match self.declar_corrente {
+            Some(declar) if esauriti || tempo => Err(DeclarationTimedOut { declar, passi: self.passi, tempo }),
+            _ => Ok(()),
+        }
+    }
+
+    fn declar_in_controllo(&self) -> NamePtr<'t> {
+        self.declar_corrente.unwrap_or(self.anonymous())
+    }
+
+    // i termini troppo profondi farebbero esaurire lo stack alle visite ricorsive
+    pub fn controllo_profondita(&self, e: ExprPtr<'t>) -> Result<(), TcError<'t>> {
+        match self.expr_info(e).depth {
+            profondita if profondita > PROFONDITA_MAX =>
+                Err(DeclarationTooDeep { declar: self.declar_in_controllo(), profondita, ricorsione: false }),
+            _ => Ok(()),
+        }
+    }
+
+    // da chiamare entrando in infer, whnf e def_eq; esci all'uscita, anche in caso di errore
+    fn entra(&mut self) -> Result<(), TcError<'t>> {
+        if self.ricorsione >= RICORSIONE_MAX {
+            return Err(DeclarationTooDeep { declar: self.declar_in_controllo(), profondita: self.ricorsione, ricorsione: true })
+        }
+        self.ricorsione += 1;
+        Ok(())
+    }
+
+    fn esci(&mut self) {
+        self.ricorsione -= 1;
+    }
+
+    fn proiezione_errata(&self, proj: ExprPtr<'t>, motivo: &'static str) -> TcError<'t> {
+        BadProjection { declar: self.declar_in_controllo(), proj, motivo }
     }
 
-    pub fn controllo_parametri(&mut self, ty: ExprPtr<'t>, ups : UparamsPtr<'t>) {
-        match self.read_expr(ty){
-            FreeVar {..} | NatLit { .. } | StrLit { .. } | Var { .. } => {}
-            Sort { universe, .. } => { 
-                assert!(self.contiene_param(universe, ups), "controllo_parametri: Sort non contiene parametri richiesti: {:?}", self.read_universe(universe));
+    // visita iterativa: i sottotermini condivisi sono controllati una volta sola
+    pub fn controllo_parametri(&self, declar: NamePtr<'t>, ty: ExprPtr<'t>, ups : UparamsPtr<'t>) -> Result<(), TcError<'t>> {
+        let mut visti = new_fx_index_set();
+        let mut pila = vec![ty];
+        while let Some(e) = pila.pop() {
+            if !visti.insert(e) {
+                continue
             }
-            Const { universes, .. } => { 
-                for u in self.read_uparams(universes).iter().copied() {
-                    assert!(self.contiene_param(u, ups), "controllo_parametri: Const con universo esterno non accettato {:?}", self.read_universe(u));
+            match self.read_expr(e){
+                FreeVar {..} | NatLit { .. } | StrLit { .. } | Var { .. } => {}
+                Sort { universe, .. } => { 
+                    if !self.contiene_param(universe, ups) {
+                        return Err(UndeclaredUniverseParam { declar, universe })
+                    }
                 }
-            }
-            App { fun, arg, .. } => {
-                self.controllo_parametri(fun, ups);
-                self.controllo_parametri(arg, ups);
-            }
-            Pi { ty, body, .. } => {
-                self.controllo_parametri(ty, ups);
-                self.controllo_parametri(body, ups);
-            }
-            Lambda { ty, body, .. } => {
-                self.controllo_parametri(ty, ups);
-                self.controllo_parametri(body, ups);
-            }
-            Let { ty, val, body, .. } => {
-                self.controllo_parametri(ty, ups);
-                self.controllo_parametri(val, ups);
-                self.controllo_parametri(body, ups);
-            }
-            Proj { structure, .. } => {
-                self.controllo_parametri(structure, ups);
+                Const { name, universes, .. } => { 
+                    if let Some(d) = self.env.declars.get(&name) {
+                        let (attesi, dati) = (self.read_uparams(d.uparams()).len(), self.read_uparams(universes).len());
+                        if attesi != dati {
+                            return Err(UniverseArityMismatch { declar, costante: name, attesi, dati })
+                        }
+                    }
+                    if let Some(&universe) = self.read_uparams(universes).iter().find(|&&u| !self.contiene_param(u, ups)) {
+                        return Err(UndeclaredUniverseParam { declar, universe })
+                    }
+                }
+                App { fun, arg, .. } => pila.extend([fun, arg]),
+                Pi { ty, body, .. } | Lambda { ty, body, .. } => pila.extend([ty, body]),
+                Let { ty, val, body, .. } => pila.extend([ty, val, body]),
+                Proj { structure, .. } => pila.push(structure),
             }
         }
+        Ok(())
     }
 
-    pub fn infer(&mut self, e: ExprPtr<'t>) -> ExprPtr<'t> {
+    pub fn infer(&mut self, e: ExprPtr<'t>) -> Result<ExprPtr<'t>, TcError<'t>> {
+        self.entra()?;
+        let span = self.apri_span();
+        let out = self.infer_core(e);
+        self.chiudi_span(span, || "infer".to_string(), || format!("\"e\": {}", e.idx));
+        self.esci();
+        out
+    }
+
+    fn infer_core(&mut self, e: ExprPtr<'t>) -> Result<ExprPtr<'t>, TcError<'t>> {
+        self.passo()?;
+        self.controllo_profondita(e)?;
+        self.statistiche.infer += 1;
         if self.infers.contains_key(&e) {
-            return self.read_infer(e)
+            self.statistiche.infer_cache += 1;
+            return Ok(self.read_infer(e))
         }
+        log!(Infer, Trace, "{}", self.fmt_expr(e));
         let out = match self.read_expr(e) {
-            Var { .. } => panic!("infer sulla var, {:?}", self.read_expr(e)),
+            Var { .. } => panic!("infer sulla var, {}", self.fmt_expr(e)),
 
             FreeVar { ty, .. } => ty,
 
@@ -93,104 +218,139 @@ impl<'t> ExportFile<'t> {
             }
             Const {name, universes, ..} => {
                 let dec =  self.read_declar(name);
+                let (attesi, dati) = (self.read_uparams(dec.uparams()).len(), self.read_uparams(universes).len());
+                if attesi != dati {
+                    return Err(UniverseArityMismatch { declar: self.declar_in_controllo(), costante: name, attesi, dati })
+                }
                 if dec.uparams() == universes {
-                    return dec.ty()
+                    return Ok(dec.ty())
                 }
+                self.controllo_profondita(dec.ty())?;
                 self.subst_expr_universes(dec.ty(), dec.uparams(), universes)
             }
             Let { ty, val, body, .. } =>  {
-                self.is_sort(ty);
-                let v = self.infer(val);
-                assert!(self.def_eq(ty, v), "Errore nella let: ty={:?}, val={:?}", self.read_expr(ty), self.read_expr(v));
+                self.is_sort(ty)?;
+                let v = self.infer(val)?;
+                if !self.def_eq(ty, v)? {
+                    let universi = self.spiega_fallimento();
+                    return Err(LetTypeMismatch { declar: self.declar_in_controllo(), ty, val_ty: v, universi })
+                }
                 let inst = self.inst(body, val, 0);
-                self.infer(inst)
-            }
-
-            Pi {ty, body, ..} => {
-                let l = self.is_sort(ty);
-                let free = self.free_var(ty);
-                let b = self.inst(body, free, 0);
-                let r = self.is_sort(b);
-                let imax = self.imax(l, r);
-                self.sort(imax)
-            }
-            Lambda {name, ty, body, ..} => {
-                self.is_sort(ty);
-                let free = self.free_var(ty);
-                let inst = self.inst(body, free, 0);  
-                let inf = self.infer(inst);
-                let abstr = self.abstr(inf, free, 0);
-                self.pi(name, ty, abstr)
+                self.infer(inst)?
+            }
+
+            Pi { .. } => {
+                let mut fvars = Vec::new();
+                let mut universi = Vec::new();
+                let mut e = e;
+                while let Pi {ty, body, ..} = self.read_expr(e) {
+                    let ty = self.inst_many(ty, &fvars, 0);
+                    universi.push(self.is_sort(ty)?);
+                    let free = self.free_var(ty);
+                    fvars.push(free);
+                    e = body;
+                }
+                let b = self.inst_many(e, &fvars, 0);
+                let mut r = self.is_sort(b)?;
+                for l in universi.into_iter().rev() {
+                    r = self.imax(l, r);
+                }
+                self.sort(r)
+            }
+            Lambda { .. } => {
+                let mut fvars = Vec::new();
+                let mut binders = Vec::new();
+                let mut e = e;
+                while let Lambda {name, ty, body, ..} = self.read_expr(e) {
+                    binders.push((name, ty));
+                    let ty = self.inst_many(ty, &fvars, 0);
+                    self.is_sort(ty)?;
+                    let free = self.free_var(ty);
+                    fvars.push(free);
+                    e = body;
+                }
+                let inst = self.inst_many(e, &fvars, 0);
+                let inf = self.infer(inst)?;
+                let mut out = self.abstr_many(inf, &fvars, 0);
+                for (name, ty) in binders.into_iter().rev() {
+                    out = self.pi(name, ty, out);
+                }
+                out
             } 
             App {fun, arg, ..} => {
-                let infer = self.infer(fun);
-                let whnf_fun = self.whnf(infer);
+                let infer = self.infer(fun)?;
+                let whnf_fun = self.whnf(infer)?;
                 match self.read_expr(whnf_fun) {
                     Pi {ty, body, ..} => {
-                        let a = self.infer(arg);
-                        assert!(self.def_eq(ty, a),  "Errore nel App(Pi)) \n ty={:?},\n a={:?}", self.read_expr(ty), self.read_expr(a));
+                        let a = self.infer(arg)?;
+                        if !self.def_eq(ty, a)? {
+                            let universi = self.spiega_fallimento();
+                            return Err(AppTypeMismatch { declar: self.declar_in_controllo(), param_ty: ty, arg_ty: a, universi })
+                        }
                         self.inst(body, arg, 0)
                     }
-                    _ => panic!("Non è stato trovato un Pi dentro App"),
+                    _ => return Err(FunctionExpected { declar: self.declar_in_controllo(), fun_ty: whnf_fun }),
                 }
             }
             
             Proj {name, idx, structure, ..} => {
-                let s = self.infer(structure);
-                let mut s = self.whnf(s);
+                let s = self.infer(structure)?;
+                let mut s = self.whnf(s)?;
                 let mut args = Vec::new();
                 while let App { fun, arg, .. } = self.read_expr(s) {
                     args.push(arg);
                     s = fun;
                 }
                 args.reverse();
-                if let Const {name : name_c, universes, .. } = self.read_expr(s) {
-                    assert_eq!(name, name_c);
-                    if let Inductive {all_ctor_names, num_indices, .. } = self.read_declar(name) {
-                        assert_eq!(all_ctor_names.len(), 1, "proj usato su un elemento che non è una struttura");
-                        assert_eq!(num_indices, 0, "proj usato su un elemento che non è una struttura");
-                        if let ctor @ Constructor{num_params, .. } = self.read_declar(all_ctor_names[0]){
-                            let mut ctor_ty = self.subst_expr_universes(ctor.ty(), ctor.uparams(), universes);
-                            for arg in args.iter().take(num_params as usize) {
-                                ctor_ty = self.whnf(ctor_ty);
-                                match self.read_expr(ctor_ty) {
-                                    Pi { body, .. } => ctor_ty = self.inst(body, *arg, 0),
-                                    _ => panic!("Proj: Non è stato trovato un pi per il param"),
-                                }
-                            }
-                            for i in 0..idx {
-                                ctor_ty = self.whnf(ctor_ty);
-                                match self.read_expr(ctor_ty) {
-                                    Pi { body, .. } => {
-                                        let p = self.proj(name, i, structure);
-                                        ctor_ty = self.inst(body, p, 0);
-                                    }
-                                    _ => panic!("Proj: Non è stato trovato un pi per l'idx"),
-                                }
-                            }
-                            ctor_ty = self.whnf(ctor_ty);
-                            match self.read_expr(ctor_ty) {
-                                Pi { ty, .. } => return ty,
-                                _ => panic!("proj: Non trovato un Pi da ritornare"),
-                            }
-                        }
-                        panic!("proj: constructor non trovato");
-                    }
-                    panic!("proj inductive non trovato");
+                let universes = match self.read_expr(s) {
+                    Const { name: name_c, universes, .. } if name_c == name => universes,
+                    _ => return Err(self.proiezione_errata(e, "il tipo della struttura non è l'induttivo della proiezione")),
+                };
+                let env = self.env;
+                let ctor = match env.declars.get(&name) {
+                    Some(Inductive { all_ctor_names, num_indices: 0, .. }) if all_ctor_names.len() == 1 => env.declars.get(&all_ctor_names[0]),
+                    _ => return Err(self.proiezione_errata(e, "l'induttivo non è una struttura")),
+                };
+                let Some(ctor @ Constructor { num_params, .. }) = ctor else {
+                    return Err(self.proiezione_errata(e, "costruttore della struttura non trovato"))
+                };
+                self.controllo_profondita(ctor.ty())?;
+                let ctor_ty = self.subst_expr_universes(ctor.ty(), ctor.uparams(), universes);
+                let params = &args[..args.len().min(*num_params as usize)];
+                let ctor_ty = self.istanzia_telescopio(ctor_ty, params)?;
+                let campi = (0..idx).map(|i| self.proj(name, i, structure)).collect::<Vec<_>>();
+                let ctor_ty = self.istanzia_telescopio(ctor_ty, &campi)?;
+                let ctor_ty = self.whnf(ctor_ty)?;
+                match self.read_expr(ctor_ty) {
+                    Pi { ty, .. } => ty,
+                    _ => return Err(self.proiezione_errata(e, "la struttura non ha questo campo")),
                 }
-                panic!("non trovata una const");
             }
         };
         self.infers.insert(e, out);
+        Ok(out)
+    }
+
+    pub fn whnf(&mut self, v: ExprPtr<'t>) -> Result<ExprPtr<'t>, TcError<'t>> {
+        self.entra()?;
+        let span = self.apri_span();
+        let out = self.whnf_core(v);
+        self.chiudi_span(span, || "whnf".to_string(), || format!("\"e\": {}", v.idx));
+        self.esci();
         out
     }
 
-    pub fn whnf(&mut self, v: ExprPtr<'t>) -> ExprPtr<'t> {
+    fn whnf_core(&mut self, v: ExprPtr<'t>) -> Result<ExprPtr<'t>, TcError<'t>> {
+        self.statistiche.whnf += 1;
         if self.whnfs.contains_key(&v) {
-            return self.read_whnf(v)
+            self.statistiche.whnf_cache += 1;
+            return Ok(self.read_whnf(v))
         }
+        log!(Whnf, Trace, "{}", self.fmt_expr(v));
         let mut e = v;
         let out = loop {
+            self.passo()?;
+            self.controllo_profondita(e)?;
             match self.read_expr(e) {
                 Let { val, body, .. } => {
                     e = self.inst(body, val, 0);
@@ -199,6 +359,8 @@ impl<'t> ExportFile<'t> {
                 Const {name, universes, ..} => {
                     let d = self.read_declar(name);
                     if let Some(v) = d.val() {
+                        // le dichiarazioni usate possono non essere state controllate
+                        self.controllo_profondita(v)?;
                         e = self.subst_expr_universes(v, d.uparams(), universes);
                     } else {
                         break e;
@@ -206,7 +368,7 @@ impl<'t> ExportFile<'t> {
                 }
                 
                 Proj { idx, structure, .. } => {
-                    let mut x = self.whnf(structure);
+                    let mut x = self.whnf(structure)?;
                     let mut args = Vec::new();
                     while let App { fun, arg, .. } = self.read_expr(x) {
                         args.push(arg);
@@ -215,7 +377,9 @@ impl<'t> ExportFile<'t> {
                     args.reverse();
                     if let Const { name, .. } = self.read_expr(x) {
                         if let Constructor { num_params, .. } = self.read_declar(name) {
-                            assert!(args.len() > (idx + num_params) as usize);
+                            if args.len() <= (idx + num_params) as usize {
+                                return Err(self.proiezione_errata(e, "il costruttore ha meno argomenti del campo proiettato"))
+                            }
                             e = args[(idx + num_params) as usize];
                         } 
                     } else {
@@ -224,85 +388,150 @@ impl<'t> ExportFile<'t> {
                 }
 
                 App { .. } => {
+                    let app = e;
                     let mut args = Vec::new();
                     while let App { fun, arg, .. } = self.read_expr(e) {
                         args.push(arg);
                         e = fun;
                     }
                     let mut args_apply = Vec::new();
-                    while let (Lambda {body, .. }, Some(pop)) = (self.read_expr(e), args.pop()) {
-                        e = body;
-                        args_apply.push(pop);
+                    while let Lambda {body, .. } = self.read_expr(e) {
+                        match args.pop() {
+                            Some(a) => {
+                                args_apply.push(a);
+                                e = body;
+                            }
+                            None => break,
+                        }
                     }
-                    for a in args_apply {
-                        e = self.inst(e, a, 0);
+                    if args_apply.is_empty() {
+                        // la testa non è una lambda: se non si riduce il termine è bloccato
+                        let testa = self.whnf(e)?;
+                        if testa == e {
+                            break app;
+                        }
+                        e = testa;
+                    } else {
+                        e = self.inst_many(e, &args_apply, 0);
                     }
-                    args.reverse();
-                    for a in args {
+                    while let Some(a) = args.pop() {
                         e = self.app(e, a);
                     }
-                    break e;
                 }
                 
                 _ => break e,
             }
         };
         self.whnfs.insert(v, out);
-        out
+        Ok(out)
+    }
+
+    // istanzia i primi vals.len() binder di un Pi, riducendo a whnf solo quando serve
+    pub fn istanzia_telescopio(&mut self, mut ty: ExprPtr<'t>, vals: &[ExprPtr<'t>]) -> Result<ExprPtr<'t>, TcError<'t>> {
+        // vals[fatti..i] sono i valori dei binder già attraversati ma non ancora sostituiti
+        let mut fatti = 0;
+        for i in 0..vals.len() {
+            if !matches!(self.read_expr(ty), Pi { .. }) {
+                ty = self.inst_many(ty, &vals[fatti..i], 0);
+                fatti = i;
+                ty = self.whnf(ty)?;
+            }
+            match self.read_expr(ty) {
+                Pi { body, .. } => ty = body,
+                _ => return Err(FunctionExpected { declar: self.declar_in_controllo(), fun_ty: ty }),
+            }
+        }
+        Ok(self.inst_many(ty, &vals[fatti..], 0))
     }
 
-   pub fn is_sort(&mut self, mut e: ExprPtr<'t>) -> UniversePtr<'t> {
+   pub fn is_sort(&mut self, mut e: ExprPtr<'t>) -> Result<UniversePtr<'t>, TcError<'t>> {
        loop {
-            let expr = self.infer(e);
-            let expr = self.whnf(expr);
+            let expr = self.infer(e)?;
+            let expr = self.whnf(expr)?;
             match self.read_expr(expr) {
-                Sort {universe, ..} => return universe,
+                Sort {universe, ..} => return Ok(universe),
                 
                 FreeVar {ty, ..} => e = ty,
                 
                 Const { name, universes, .. } => {
                     let d = self.read_declar(name);
+                    self.controllo_profondita(d.ty())?;
                     e = self.subst_expr_universes(d.ty(), d.uparams(), universes);
                 }
                 
-                NatLit {..} | StrLit {..} => panic!("valore non Sort, {:?}", self.read_expr(expr)),
+                NatLit {..} | StrLit {..} => return Err(SortExpected { declar: self.declar_in_controllo(), e, ty: expr }),
             
                 _ => e = expr,
             }
        }
     }
 
-    pub fn def_eq(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> bool {
-        println!("def_EQ \n ty={:?},\n a={:?}", self.read_expr(x), self.read_expr(y));
-
-        if x == y {
-            return true;
+    pub fn def_eq(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> Result<bool, TcError<'t>> {
+        self.statistiche.def_eq += 1;
+        if x == y || self.equivalenze.equivalenti(x, y) {
+            self.statistiche.def_eq_cache += 1;
+            return Ok(true)
+        }
+        if let Some(fallimento) = self.def_eq_falliti.get(&(x, y)) {
+            self.statistiche.def_eq_cache += 1;
+            self.fallimento_universo = *fallimento;
+            return Ok(false)
         }
+        // il fallimento di un confronto riuscito non spiega niente
+        let esterno = self.fallimento_universo.take();
+        self.entra()?;
+        let span = self.apri_span();
+        let esito = self.def_eq_core(x, y);
+        self.chiudi_span(span, || "def_eq".to_string(), || format!("\"x\": {}, \"y\": {}, \"esito\": {}", x.idx, y.idx,
+            esito.as_ref().map_or("null".to_string(), |b| b.to_string())));
+        self.esci();
+        let out = esito?;
+        log!(DefEq, Debug, "{} =?= {}: {}", x.idx, y.idx, out);
+        if out {
+            self.equivalenze.unisci(x, y);
+            self.fallimento_universo = esterno;
+        } else {
+            self.def_eq_falliti.insert((x, y), self.fallimento_universo);
+        }
+        Ok(out)
+    }
+
+    fn def_eq_core(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> Result<bool, TcError<'t>> {
+        self.passo()?;
+        self.controllo_profondita(x)?;
+        self.controllo_profondita(y)?;
+        log!(DefEq, Trace, "{}\n  =?= {}", self.fmt_expr_rientro(x, 6), self.fmt_expr_rientro(y, 6));
 
         match self.read_expr_pair(x, y){
 
             ( Var { .. }, _ ) | ( _, Var { .. } ) => panic!("trovato Var durante il def_eq"),
 
-            ( FreeVar {idx: idx_x, .. }, FreeVar {idx: idx_y, ..} ) => return idx_x == idx_y,
+            ( FreeVar {idx: idx_x, .. }, FreeVar {idx: idx_y, ..} ) => return Ok(idx_x == idx_y),
             
-            ( Sort {universe : u_x, .. }, Sort {universe : u_y, ..} ) => return self.leq(u_x, u_y, 0) && self.leq(u_y, u_x, 0),
+            ( Sort {universe : u_x, .. }, Sort {universe : u_y, ..} ) => {
+                if self.eq_universe(u_x, u_y) {
+                    return Ok(true)
+                }
+                self.registra_fallimento(u_x, u_y);
+                return Ok(false)
+            }
             
-            ( Const {name: n1, universes: u1, ..}, Const {name: n2, universes: u2, ..}) if n1 == n2 => return self.leq_many(u1, u2),
+            ( Const {name: n1, universes: u1, ..}, Const {name: n2, universes: u2, ..}) if n1 == n2 => return Ok(self.leq_many(u1, u2)),
             
             ( Const {..}, Const {..}) => {
-                if self.unit_like(x,y) || self.unit_like(y, x) {
-                    return true
+                if self.speculativo(|tc| Ok(tc.unit_like(x, y)? || tc.unit_like(y, x)?))? {
+                    return Ok(true)
                 }
             }
             
             ( App {fun: f1, arg: a1, ..}, App {fun: f2, arg: a2, ..}) => {
-                if self.def_eq(f1, f2) {
+                if self.def_eq(f1, f2)? {
                     return self.def_eq(a1, a2 )
                 }
             }
 
             ( Lambda {ty: ty1, body: b1, ..}, Lambda {ty: ty2, body: b2, ..}) | ( Pi {ty: ty1, body: b1, ..}, Pi {ty: ty2, body: b2, ..}) => {
-                if self.def_eq(ty1, ty2) {
+                if self.def_eq(ty1, ty2)? {
                     let free = self.free_var(ty1);
                     let a = self.inst(b1, free, 0);
                     let b = self.inst(b2, free, 0);
@@ -311,7 +540,7 @@ impl<'t> ExportFile<'t> {
             }
             
             ( Let {val: v1, body: b1, ..}, Let {val: v2, body: b2, ..} ) => {
-                if self.def_eq(v1, v2) {
+                if self.def_eq(v1, v2)? {
                     let x1 = self.inst(b1, v1, 0);
                     let y1 = self.inst(b2, v2, 0);
                     return self.def_eq(x1, y1)
@@ -319,13 +548,13 @@ impl<'t> ExportFile<'t> {
             }
             
             ( Proj { name: n1, idx: i1, structure: s1, .. }, Proj { name: n2, idx: i2, structure: s2, .. }) => {
-                return n1 == n2 && i1 == i2 && self.def_eq(s1, s2);
+                return Ok(n1 == n2 && i1 == i2 && self.def_eq(s1, s2)?);
             }
         
 
             ( Lambda {..}, _ ) => {
-                let i_y = self.infer(y);
-                let whnf_y = self.whnf(i_y);
+                let i_y = self.infer(y)?;
+                let whnf_y = self.whnf(i_y)?;
                 if let Pi {name, ty, .. } = self.read_expr(whnf_y) {
                     let var = self.var(0);
                     let lambda = self.lambda(name, ty, var);
@@ -335,8 +564,8 @@ impl<'t> ExportFile<'t> {
             }
             
             ( _, Lambda {..} ) => {
-                let i_x = self.infer(x);
-                let whnf_x = self.whnf(i_x);
+                let i_x = self.infer(x)?;
+                let whnf_x = self.whnf(i_x)?;
                 if let Pi {name, ty, .. } = self.read_expr(whnf_x) {
                     let var = self.var(0);
                     let lambda = self.lambda(name, ty, var);
@@ -346,67 +575,76 @@ impl<'t> ExportFile<'t> {
             }
             
             (App {..}, _) => {
-                if self.def_eq_struct(y,x) {
-                    return true
+                if self.speculativo(|tc| tc.def_eq_struct(y, x))? {
+                    return Ok(true)
                 }
             }
             
             (_, App {..} ) => {
-                if self.def_eq_struct(x,y) {
-                    return true
+                if self.speculativo(|tc| tc.def_eq_struct(x, y))? {
+                    return Ok(true)
                 }
             }
         
             _ => {}
         }
 
-        if self.proof_irrelevant(x, y) || self.proof_irrelevant(y, x) {
-            return true
+        if self.speculativo(|tc| Ok(tc.proof_irrelevant(x, y)? || tc.proof_irrelevant(y, x)?))? {
+            return Ok(true)
         }
 
-        let whnf_x = self.whnf(x);
-        let whnf_y = self.whnf(y);
+        let whnf_x = self.whnf(x)?;
+        let whnf_y = self.whnf(y)?;
 
         if whnf_x != x || whnf_y != y {
             return self.def_eq(whnf_x, whnf_y);
         }
         
-        false
+        Ok(false)
     }
 
-    pub fn unit_like(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> bool {
+    // un tentativo che può fallire senza spiegare il fallimento del confronto in corso
+    fn speculativo(&mut self, f: impl FnOnce(&mut Self) -> Result<bool, TcError<'t>>) -> Result<bool, TcError<'t>> {
+        let prima = self.fallimento_universo;
+        let out = f(self)?;
+        self.fallimento_universo = prima;
+        Ok(out)
+    }
+
+    pub fn unit_like(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> Result<bool, TcError<'t>> {
         if let Const{name: n1, universes, ..} = self.read_expr(x){
             if let Inductive{all_ctor_names, num_indices, .. } = self.read_declar(n1){
                 if all_ctor_names.len() == 1 && num_indices == 0 {
-                    let infer_x = self.infer(x);
-                    let infer_y = self.infer(y);
-                    if self.def_eq(infer_x, infer_y) {
+                    let infer_x = self.infer(x)?;
+                    let infer_y = self.infer(y)?;
+                    if self.def_eq(infer_x, infer_y)? {
                         if let Constructor{uparams, ..} = self.read_declar(all_ctor_names[0]) {
                             let u1 = self.read_uparams(universes);
                             let u2 = self.read_uparams(uparams);
                             if u1.len() == u2.len() {
-                                return true
+                                return Ok(true)
                             }
                         }
                     }
                 }
             }
         }
-        false
+        Ok(false)
     }
     
-    fn proof_irrelevant(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> bool { 
-        let infer_x = self.infer(x);
-        let infer_y = self.infer(y);
-        if self.is_sort(infer_x) == self.zero() {
-            if self.is_sort(infer_y) == self.zero() {
+    fn proof_irrelevant(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> Result<bool, TcError<'t>> { 
+        let infer_x = self.infer(x)?;
+        let infer_y = self.infer(y)?;
+        let (u_x, u_y) = (self.is_sort(infer_x)?, self.is_sort(infer_y)?);
+        if self.eq_universe(u_x, self.zero()) {
+            if self.eq_universe(u_y, self.zero()) {
                 return self.def_eq(infer_x, infer_y)
             }
         }
-        false
+        Ok(false)
     }
 
-    pub fn def_eq_struct(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> bool {
+    pub fn def_eq_struct(&mut self, x: ExprPtr<'t>, y: ExprPtr<'t>) -> Result<bool, TcError<'t>> {
         let mut args = Vec::new();
         let mut s = y;
         while let App { fun, arg, .. } = self.read_expr(s) {
@@ -420,22 +658,244 @@ impl<'t> ExportFile<'t> {
                     if all_ctor_names.len() == 1 && num_indices == 0 {
                         let num = (num_params + num_fields) as usize;
                         if args.len() == num {
-                            let inf_x = self.infer(x);
-                            let inf_y = self.infer(y);
-                            if self.def_eq(inf_x, inf_y) {    
+                            let inf_x = self.infer(x)?;
+                            let inf_y = self.infer(y)?;
+                            if self.def_eq(inf_x, inf_y)? {    
                                 for i in 0..num_fields as usize {
                                     let proj = self.proj(parent, num_params + i as u32, x);
-                                    if !self.def_eq(proj, args[i + num_params as usize]) {
-                                        return false
+                                    if !self.def_eq(proj, args[i + num_params as usize])? {
+                                        return Ok(false)
                                     }
                                 }
-                                return true
+                                return Ok(true)
                             }
                         }
                     }
                 }
             }
         }
-        false
+        Ok(false)
+    }
+}
+
+// oltre questi livelli la dichiarazione fallisce invece di esaurire lo stack
+pub const PROFONDITA_MAX: u32 = 20_000;
+pub const RICORSIONE_MAX: u32 = 20_000;
+// stack dei thread che controllano le dichiarazioni, abbastanza per i limiti qui sopra
+pub const PILA: usize = 1 << 30;
+
+// budget di ogni dichiarazione, None per nessun limite
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limiti {
+    pub passi: Option<u64>,
+    pub tempo: Option<Duration>,
+}
+
+// esito del controllo di una dichiarazione; il messaggio è già formattato
+// perché può contenere termini creati dal type checker che l'ha controllata
+#[derive(Debug, Clone)]
+pub struct Risultato {
+    pub indice: usize,
+    pub errore: Option<(&'static str, String)>,
+    pub tempo: Duration,
+    pub statistiche: Statistiche,
+}
+
+fn riepilogo(risultati: &[Risultato]) {
+    let errori = risultati.iter().filter(|r| r.errore.is_some()).count();
+    if errori == 0 {
+        println!("\nNessun errore nelle dichiarazioni");
+    } else {
+        println!("\nTrovati errori in {} dichiarazioni", errori);
+    }
+}
+
+// stato condiviso dai worker di check_declars_parallel
+struct Coda {
+    pronte: VecDeque<usize>,
+    // dipendenze non ancora controllate di ogni dichiarazione
+    mancanti: Vec<usize>,
+    finite: usize,
+    risultati: Vec<Option<Risultato>>,
+    panico: Option<Box<dyn std::any::Any + Send>>,
+}
+
+impl<'t> Environment<'t> {
+    // per ogni dichiarazione in indici, le posizioni in indici delle dichiarazioni
+    // precedenti che usa; quelle fuori da indici non contano
+    pub fn dipendenze(&self, indici: &[usize], jobs: usize) -> Vec<Vec<usize>> {
+        let mut posizioni = vec![None; self.declars.len()];
+        for (k, &i) in indici.iter().enumerate() {
+            posizioni[i] = Some(k);
+        }
+        let posizioni = &posizioni;
+        let blocco = indici.len().div_ceil(jobs.max(1)).max(1);
+        let mut out = vec![Vec::new(); indici.len()];
+        thread::scope(|s| {
+            for (parte, idx) in out.chunks_mut(blocco).zip(indici.chunks(blocco)) {
+                s.spawn(move || {
+                    let tc = TypeChecker::new(self);
+                    for (deps, &pos) in parte.iter_mut().zip(idx) {
+                        let d = &self.declars[pos];
+                        let mut usate = new_fx_index_set();
+                        tc.costanti(d.ty(), &mut usate);
+                        if let Some(val) = d.val() {
+                            tc.costanti(val, &mut usate);
+                        }
+                        *deps = usate.iter()
+                            .filter_map(|c| self.declars.get_index_of(c))
+                            .filter(|&i| i < pos)
+                            .filter_map(|i| posizioni[i])
+                            .collect();
+                    }
+                });
+            }
+        });
+        out
+    }
+
+    // come check_declars, ma con jobs thread: una dichiarazione viene controllata
+    // quando sono finite quelle da cui dipende; ogni thread ha il suo type checker,
+    // e con una traccia il suo profilo
+    pub fn check_declars_parallel(&self, indici: &[usize], jobs: usize, limiti: Limiti, traccia: Option<Traccia>) -> (Vec<Risultato>, Vec<Profilo>) {
+        let n = indici.len();
+        let dipendenze = self.dipendenze(indici, jobs);
+        let mut dipendenti = vec![Vec::new(); n];
+        for (i, deps) in dipendenze.iter().enumerate() {
+            for &d in deps {
+                dipendenti[d].push(i);
+            }
+        }
+        let coda = Mutex::new(Coda {
+            pronte: (0..n).filter(|&i| dipendenze[i].is_empty()).collect(),
+            mancanti: dipendenze.iter().map(|d| d.len()).collect(),
+            finite: 0,
+            risultati: vec![None; n],
+            panico: None,
+        });
+        let cambiata = Condvar::new();
+
+        let profili = thread::scope(|s| {
+            let worker = (1..=jobs as u32).map(|tid| {
+                let (coda, cambiata, dipendenti) = (&coda, &cambiata, &dipendenti);
+                thread::Builder::new().stack_size(PILA).spawn_scoped(s, move || {
+                    let mut tc = TypeChecker::new(self);
+                    tc.limiti = limiti;
+                    tc.profilo = traccia.map(|t| Profilo::nuovo(t, tid));
+                    loop {
+                        let i = {
+                            let mut c = coda.lock().unwrap();
+                            loop {
+                                if c.panico.is_some() || c.finite == n {
+                                    return tc.profilo
+                                }
+                                if let Some(i) = c.pronte.pop_front() {
+                                    break i
+                                }
+                                c = cambiata.wait(c).unwrap();
+                            }
+                        };
+                        let esito = catch_unwind(AssertUnwindSafe(|| tc.check_declar(indici[i])));
+                        let mut c = coda.lock().unwrap();
+                        match esito {
+                            Ok(r) => {
+                                c.risultati[i] = Some(r);
+                                c.finite += 1;
+                                for &j in &dipendenti[i] {
+                                    c.mancanti[j] -= 1;
+                                    if c.mancanti[j] == 0 {
+                                        c.pronte.push_back(j);
+                                    }
+                                }
+                            }
+                            // il messaggio è già stato stampato, si fermano tutti
+                            Err(p) => c.panico = Some(p),
+                        }
+                        cambiata.notify_all();
+                    }
+                }).unwrap()
+            }).collect::<Vec<_>>();
+            // un worker che va in panico lo segnala nella coda
+            worker.into_iter().filter_map(|w| w.join().ok().flatten()).collect::<Vec<_>>()
+        });
+
+        let coda = coda.into_inner().unwrap();
+        if let Some(p) = coda.panico {
+            resume_unwind(p);
+        }
+        let risultati = coda.risultati.into_iter().flatten().collect::<Vec<_>>();
+        for (_, messaggio) in risultati.iter().filter_map(|r| r.errore.as_ref()) {
+            println!("{}", messaggio);
+        }
+        riepilogo(&risultati);
+        (risultati, profili)
+    }
+
+    // assiomi da cui dipende ogni dichiarazione, anche indirettamente, come indici in declars
+    pub fn assiomi(&self, jobs: usize) -> Vec<Vec<usize>> {
+        let tutte = (0..self.declars.len()).collect::<Vec<_>>();
+        let dipendenze = self.dipendenze(&tutte, jobs);
+        let mut out: Vec<Vec<usize>> = Vec::with_capacity(tutte.len());
+        // le dipendenze precedono sempre la dichiarazione
+        for (i, deps) in dipendenze.iter().enumerate() {
+            let mut assiomi = new_fx_index_set();
+            if matches!(self.declars[i], Axiom { .. }) {
+                assiomi.insert(i);
+            }
+            for &d in deps {
+                assiomi.extend(out[d].iter().copied());
+            }
+            let mut assiomi = assiomi.into_iter().collect::<Vec<_>>();
+            assiomi.sort_unstable();
+            out.push(assiomi);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whnf_beta_con_testa_bloccata() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let anon = tc.anonymous();
+        let prop = tc.sort(tc.zero());
+        let a_ty = tc.free_var(prop);
+        let f_ty = tc.pi(anon, a_ty, a_ty);
+        let (f, a) = (tc.free_var(f_ty), tc.free_var(a_ty));
+        // (fun x => f x) a
+        let x = tc.var(0);
+        let corpo = tc.app(f, x);
+        let lambda = tc.lambda(anon, a_ty, corpo);
+        let redex = tc.app(lambda, a);
+        let atteso = tc.app(f, a);
+        assert_eq!(tc.whnf(redex).unwrap(), atteso);
+        // anche dalla cache
+        assert_eq!(tc.whnf(redex).unwrap(), atteso);
+        assert_eq!(tc.read_whnf(redex), atteso);
+    }
+
+    #[test]
+    fn whnf_beta_con_argomenti_in_piu() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let anon = tc.anonymous();
+        let prop = tc.sort(tc.zero());
+        let a_ty = tc.free_var(prop);
+        let a_a = tc.pi(anon, a_ty, a_ty);
+        let f_ty = tc.pi(anon, a_ty, a_a);
+        let (f, a, b) = (tc.free_var(f_ty), tc.free_var(a_ty), tc.free_var(a_ty));
+        // (fun x => f x) a b
+        let x = tc.var(0);
+        let corpo = tc.app(f, x);
+        let lambda = tc.lambda(anon, a_ty, corpo);
+        let redex = tc.app(lambda, a);
+        let redex = tc.app(redex, b);
+        let fa = tc.app(f, a);
+        let atteso = tc.app(fa, b);
+        assert_eq!(tc.whnf(redex).unwrap(), atteso);
     }
 }
@@ -1,7 +1,7 @@
 use std::hash::{Hash, Hasher};
-use crate::util::{Ptr, ExportFile};
-use crate::name::NamePtr;
-use crate::hash64;
+use crate::util::{Ptr, TypeChecker, FxIndexMap, new_fx_index_map};
+use crate::name::{Name, NamePtr};
+use crate::{hash64, log};
 use Universe::*;
 
 pub const ZERO_HASH: u64 = 10;
@@ -13,12 +13,28 @@ pub const PARAM_HASH: u64 = 14;
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Universe<'a> {
     Zero,
-    Succ { pred :UniversePtr<'a>, hash: u64 },
-    Max { v1: UniversePtr<'a>, v2: UniversePtr<'a>, hash: u64 },
-    IMax { v1: UniversePtr<'a>, v2: UniversePtr<'a>, hash: u64 },
+    Succ { pred :UniversePtr<'a>, hash: u64, info: InfoUniverso },
+    Max { v1: UniversePtr<'a>, v2: UniversePtr<'a>, hash: u64, info: InfoUniverso },
+    IMax { v1: UniversePtr<'a>, v2: UniversePtr<'a>, hash: u64, info: InfoUniverso },
     Param { name:NamePtr<'a>, hash: u64},
 }
 
+// Dati calcolati alla costruzione di ogni universo a partire dai figli.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoUniverso {
+    pub profondita: u32,
+    pub has_param: bool,
+}
+
+impl InfoUniverso {
+    fn nodo(figli: &[InfoUniverso]) -> InfoUniverso {
+        InfoUniverso {
+            profondita: figli.iter().map(|f| f.profondita).max().unwrap_or(0).saturating_add(1),
+            has_param: figli.iter().any(|f| f.has_param),
+        }
+    }
+}
+
 impl<'a> Universe<'a> {
     fn get_hash(&self) -> u64 {
         match self {
@@ -37,23 +53,46 @@ pub type UniversePtr<'a> = Ptr<&'a Universe<'a>>;
 
 pub type UparamsPtr<'a> = Ptr<&'a Vec<UniversePtr<'a>>>;
 
-impl<'t> ExportFile<'t> {
+pub type FormaNormale<'a> = Vec<(UniversePtr<'a>, u32)>;
+
+pub type Assegnamento<'a> = FxIndexMap<NamePtr<'a>, u64>;
+
+// Vincolo di universo fallito durante il def_eq: l e r sono in forma normale e l > r con ass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FallimentoUniverso<'a> {
+    pub l: UniversePtr<'a>,
+    pub r: UniversePtr<'a>,
+    pub ass: Assegnamento<'a>,
+}
+
+impl<'e, 't> TypeChecker<'e, 't> {
     
     pub fn zero(&self) -> UniversePtr<'t> { Ptr::from(0) }
     
+    pub fn info_universo(&self, universe: UniversePtr<'t>) -> InfoUniverso {
+        match self.read_universe(universe) {
+            Zero => InfoUniverso { profondita: 1, has_param: false },
+            Param{..} => InfoUniverso { profondita: 1, has_param: true },
+            Succ{info, ..} | Max{info, ..} | IMax{info, ..} => info,
+        }
+    }
+
     pub fn succ(&mut self, pred: UniversePtr<'t>) -> UniversePtr<'t> {
         let hash = hash64!(SUCC_HASH, pred);
-        self.alloc_universe(Succ{pred, hash})
+        let info = InfoUniverso::nodo(&[self.info_universo(pred)]);
+        self.alloc_universe(Succ{pred, hash, info})
     }
 
     pub fn max(&mut self, v1: UniversePtr<'t>, v2: UniversePtr<'t>) -> UniversePtr<'t> {
         let hash = hash64!(MAX_HASH, v1, v2);
-        self.alloc_universe(Max{v1, v2, hash})
+        let info = InfoUniverso::nodo(&[self.info_universo(v1), self.info_universo(v2)]);
+        self.alloc_universe(Max{v1, v2, hash, info})
     }
     
     pub fn imax(&mut self, v1: UniversePtr<'t>, v2: UniversePtr<'t>) -> UniversePtr<'t> {
         let hash = hash64!(IMAX_HASH, v1, v2);
-        self.alloc_universe(IMax{v1, v2, hash})
+        let info = InfoUniverso::nodo(&[self.info_universo(v1), self.info_universo(v2)]);
+        self.alloc_universe(IMax{v1, v2, hash, info})
     }
     
     pub fn param(&mut self, name: NamePtr<'t>) -> UniversePtr<'t> {
@@ -61,55 +100,114 @@ impl<'t> ExportFile<'t> {
         self.alloc_universe(Param{name, hash})
     }
 
-    fn riduci(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>, da_max: bool) -> UniversePtr<'t> {
-        match (self.read_universe(l), self.read_universe(r)) {
-            (Zero, _) => r,
-            (_, Zero) => l,
-            (Succ{pred : p1, ..}, Succ{pred : p2, ..}) => {
-                let prec = self.riduci(p1, p2, false);
-                self.succ(prec)
+    // Forma normale: max ordinato di termini `base + k`. La base è Zero (termine costante),
+    // un Param, oppure un imax bloccato `imax a u` con u Param.
+    pub fn forma_normale(&mut self, ptr: UniversePtr<'t>) -> FormaNormale<'t> {
+        let mut u = ptr;
+        let mut k = 0;
+        while let Succ{pred, ..} = self.read_universe(u) {
+            u = pred;
+            k += 1;
+        }
+        let mut out = match self.read_universe(u) {
+            Zero => Vec::new(),
+            Param{..} => vec![(u, 0)],
+            Succ{..} => unreachable!(),
+            Max{v1, v2, ..} => {
+                let l = self.forma_normale(v1);
+                let r = self.forma_normale(v2);
+                self.unisci(l, r)
             }
-            (Max{..},_) |(IMax{..},_) | (_, Max{..}) | (_, IMax{..}) => {
-                let r = self.semplifica(r);
-                let l = self.semplifica(l);
-                if da_max { self.max(l,r) }
-                else { self.riduci(l, r, true) }
+            IMax{v1, v2, ..} => {
+                let l = self.forma_normale(v1);
+                let r = self.forma_normale(v2);
+                // imax a (max b c) = max (imax a b) (imax a c), e imax a 0 = 0
+                let mut out = Vec::new();
+                for (base, k) in r {
+                    let termine = if k > 0 {
+                        self.unisci(l.clone(), vec![(base, k)])
+                    } else {
+                        match self.read_universe(base) {
+                            Param{..} => self.imax_bloccato(&l, base),
+                            // imax a (imax b u) = max (imax a u) (imax b u)
+                            IMax{v2: p, ..} => {
+                                let a = self.imax_bloccato(&l, p);
+                                self.unisci(a, vec![(base, 0)])
+                            }
+                            _ => unreachable!(),
+                        }
+                    };
+                    out = self.unisci(out, termine);
+                }
+                out
             }
-            (Param{..},_)  => {
-                let r = self.semplifica(r);
-                self.max(l, r)
+        };
+        if k > 0 {
+            if out.is_empty() {
+                out.push((self.zero(), 0));
             }
-            (_,Param{..}) => {
-                let l = self.semplifica(l);
-                self.max(l, r)
+            for t in out.iter_mut() {
+                t.1 += k;
             }
         }
+        out
     }
 
-    pub fn semplifica(&mut self, ptr: UniversePtr<'t>) -> UniversePtr<'t> {
-        match self.read_universe(ptr) {
-            Zero | Param{..} => ptr,
-            Succ{pred, ..} => {
-                let val = self.semplifica(pred);
-                self.succ(val)
-            }
-            Max{v1, v2, ..} => {
-                let l = self.semplifica(v1);
-                let r = self.semplifica(v2);
-                self.riduci(l, r, false)
+    fn imax_bloccato(&mut self, l: &FormaNormale<'t>, p: UniversePtr<'t>) -> FormaNormale<'t> {
+        if l.iter().all(|t| *t == (p, 0)) {
+            return vec![(p, 0)]
+        }
+        let l = self.ricostruisci(l);
+        let imax = self.imax(l, p);
+        vec![(imax, 0)]
+    }
+
+    fn unisci(&self, mut l: FormaNormale<'t>, r: FormaNormale<'t>) -> FormaNormale<'t> {
+        for (base, k) in r {
+            match l.binary_search_by_key(&base.idx, |t| t.0.idx) {
+                Ok(i) => l[i].1 = l[i].1.max(k),
+                Err(i) => l.insert(i, (base, k)),
             }
-            IMax{v1, v2, ..} => {
-                let l = self.semplifica(v1);
-                let r = self.semplifica(v2);
-                if self.leq(r, self.zero(), 0) {
-                    self.zero()
-                } else {
-                    self.riduci(l, r, false)
-                }
+        }
+        // la costante è superflua se un altro termine ha un offset almeno uguale
+        if let Some(&(base, c)) = l.first()
+            && base == self.zero() && l[1..].iter().any(|t| t.1 >= c) {
+            l.remove(0);
+        }
+        l
+    }
+
+    pub fn ricostruisci(&mut self, nf: &FormaNormale<'t>) -> UniversePtr<'t> {
+        let mut out = None;
+        for &(base, k) in nf.iter().rev() {
+            let mut t = base;
+            for _ in 0..k {
+                t = self.succ(t);
             }
+            out = Some(match out {
+                None => t,
+                Some(r) => self.max(t, r),
+            });
         }
+        out.unwrap_or(self.zero())
+    }
+
+    pub fn semplifica(&mut self, ptr: UniversePtr<'t>) -> UniversePtr<'t> {
+        let nf = self.forma_normale(ptr);
+        self.ricostruisci(&nf)
+    }
+
+    fn param_bloccato(&self, nf: &FormaNormale<'t>) -> Option<UniversePtr<'t>> {
+        nf.iter().find_map(|&(base, _)| match self.read_universe(base) {
+            IMax{v2, ..} => Some(v2),
+            _ => None,
+        })
     }
     
+    pub fn has_param(&self, universe: UniversePtr<'t>) -> bool {
+        self.info_universo(universe).has_param
+    }
+
     pub fn contiene_param(&self, universe: UniversePtr<'t>, params: UparamsPtr<'t>) -> bool {
         match self.read_universe(universe) {
             Zero => true,
@@ -123,58 +221,149 @@ impl<'t> ExportFile<'t> {
     }
 
     pub fn leq_many(&mut self, xs: UparamsPtr<'t>, ys: UparamsPtr<'t>) -> bool {
-        let xs = self.read_uparams(xs).clone();
-        let ys = self.read_uparams(ys).clone();
+        let xs = self.read_uparams(xs);
+        let ys = self.read_uparams(ys);
         if xs.len() != ys.len() {
             return false
         }
-        if xs.is_empty() {
-            return true
+        for (x, y) in xs.iter().copied().zip(ys.iter().copied()) {
+            if !self.eq_universe(x, y) {
+                self.registra_fallimento(x, y);
+                return false
+            }
         }
-        xs.iter().copied().zip(ys.iter().copied()).all(|(x, y)| self.leq(x, y, 0) && self.leq(y, x, 0))
+        true
     }
-    
-    pub fn leq(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>, deph_r: isize) -> bool {
+
+    pub fn eq_universe(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> bool {
+        l == r || self.semplifica(l) == self.semplifica(r) || (self.leq(l, r) && self.leq(r, l))
+    }
+
+    // da chiamare quando l = r è falso; il controesempio si cerca solo se il fallimento viene riportato
+    pub fn registra_fallimento(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>) {
+        log!(Universe, Debug, "{} ≠ {}", self.fmt_universe(l), self.fmt_universe(r));
+        self.fallimento_universo = Some((l, r));
+    }
+
+    // l'ultimo vincolo registrato, con le forme normali e un assegnamento che le distingue
+    pub fn spiega_fallimento(&mut self) -> Option<FallimentoUniverso<'t>> {
+        let (l, r) = self.fallimento_universo.take()?;
+        let (l, r) = if self.leq(l, r) { (r, l) } else { (l, r) };
+        let ass = self.controesempio(l, r).expect("spiega_fallimento: i livelli sono uguali");
         let l = self.semplifica(l);
         let r = self.semplifica(r);
-        let out = match (self.read_universe(l), self.read_universe(r)) {
-            (Zero, _) if deph_r >= 0 => true,
-            (_, Zero) if deph_r < 0 => false,
-            (Zero, Zero) => deph_r >= 0,
-            
-            (Param{..}, Zero) => false,
-            (Zero, Param{..}) => deph_r >= 0,
-            (Param {name : n1, ..}, Param{name : n2, ..}) => n1 == n2 && deph_r >= 0,
+        Some(FallimentoUniverso { l, r, ass })
+    }
 
-            (Succ{pred : s1, ..}, Succ {pred : s2, ..} ) => self.leq(s1, s2, deph_r),
-            (Succ{pred, ..}, _) => self.leq(pred, r, deph_r - 1),
-            (_, Succ {pred, ..}) => self.leq(l, pred, deph_r + 1),
-            
-            (IMax{v2, .. }, _) if self.leq(v2, self.zero(), 0) => self.leq(self.zero(), r, deph_r),
-            (_, IMax{v2, .. }) if self.leq(v2, self.zero(), 0) => self.leq(l, self.zero(), deph_r),
+    pub fn leq(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> bool {
+        log!(Universe, Trace, "{} ≤ {}", self.fmt_universe(l), self.fmt_universe(r));
+        let span = self.apri_span();
+        let out = self.controesempio_aux(l, r).is_none();
+        self.chiudi_span(span, || "leq".to_string(), || format!("\"l\": {}, \"r\": {}", l.idx, r.idx));
+        out
+    }
 
-            (IMax{v1, v2, ..}, IMax{v1: s1, v2 : s2, ..}) |
-            (Max{v1, v2, ..}, Max{v1: s1, v2: s2, ..}) if ( v1 == s1 && v2 == s2 ) || ( v1 == s2 && v2 == s1 ) => deph_r >= 0,
-            
+    // Un assegnamento dei parametri di l e r per cui l > r, se esiste.
+    pub fn controesempio(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> Option<Assegnamento<'t>> {
+        let mut ass = self.controesempio_aux(l, r)?;
+        let mut params = Vec::new();
+        self.params_universe(l, &mut params);
+        self.params_universe(r, &mut params);
+        for p in params {
+            ass.entry(p).or_insert(0);
+        }
+        debug_assert!(self.eval_universe(l, &ass) > self.eval_universe(r, &ass));
+        Some(ass)
+    }
 
-            (IMax{v2, .. }, _) | (_, IMax{v2, .. }) if matches!(self.read_universe(v2), Param{..})  => {
+    // Gli imax bloccati su un parametro u vengono eliminati separando i casi u = 0 e u = succ u.
+    fn controesempio_aux(&mut self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> Option<Assegnamento<'t>> {
+        let nl = self.forma_normale(l);
+        let nr = self.forma_normale(r);
+        match self.param_bloccato(&nl).or_else(|| self.param_bloccato(&nr)) {
+            Some(p) => {
+                let Param{name, ..} = self.read_universe(p) else { unreachable!() };
+                let param = self.alloc_uparams(vec![p]);
                 let zero = self.alloc_uparams(vec![self.zero()]);
-                let succ_v = self.succ(v2);
-                let succ = self.alloc_uparams(vec![succ_v]);
-                let param = self.alloc_uparams(vec![v2]);
-//controllo cosa sucerebbe se 0 o un succ
-                let lz = self.subst_universe(l, param, zero);
-                let rz = self.subst_universe(r, param, zero);
-                let ls = self.subst_universe(l, param, succ);
-                let rs = self.subst_universe(r, param, succ);
-                return self.leq(lz, rz, deph_r) && self.leq(ls, rs, deph_r);
-
+                let succ_p = self.succ(p);
+                let succ = self.alloc_uparams(vec![succ_p]);
+                for (caso, incremento) in [(zero, None), (succ, Some(1))] {
+                    let l = self.subst_universe(l, param, caso);
+                    let r = self.subst_universe(r, param, caso);
+                    if let Some(mut ass) = self.controesempio_aux(l, r) {
+                        // nel caso succ il valore interno del parametro è u - 1
+                        let v = incremento.map_or(0, |i| ass.get(&name).copied().unwrap_or(0) + i);
+                        ass.insert(name, v);
+                        return Some(ass)
+                    }
+                }
+                None
             }
-            
-            (Max{v1, v2, ..}, _) | (IMax{v1, v2, ..}, _) => self.leq(v1, r, deph_r) && self.leq(v2, r, deph_r),
-            (_, Max{v1, v2, ..}) | (_, IMax{v1, v2, ..}) => self.leq(l, v1, deph_r) || self.leq(l, v2, deph_r),
+            None => self.controesempio_forme(&nl, &nr),
+        }
+    }
+
+    // Senza imax ogni termine di l deve essere coperto da un termine di r: p + k da p + k' con k' >= k,
+    // la costante k da un qualunque termine con offset >= k. Se p + k non è coperto basta dare a p
+    // un valore più grande di tutti gli offset di r, se la costante non è coperta basta tutto a 0.
+    fn controesempio_forme(&self, nl: &FormaNormale<'t>, nr: &FormaNormale<'t>) -> Option<Assegnamento<'t>> {
+        let zero = self.zero();
+        let &(base, _) = nl.iter().find(|&&(base, k)| {
+            !nr.iter().any(|&(b, k2)| k2 >= k && (base == zero || b == base))
+        })?;
+        let mut ass: Assegnamento<'t> = new_fx_index_map();
+        if let Param{name, ..} = self.read_universe(base) {
+            let n = nr.iter().map(|t| t.1 as u64).max().unwrap_or(0) + 1;
+            ass.insert(name, n);
+        }
+        Some(ass)
+    }
+
+    pub fn fmt_universe(&self, universe: UniversePtr<'t>) -> String {
+        let mut u = universe;
+        let mut k = 0;
+        while let Succ{pred, ..} = self.read_universe(u) {
+            u = pred;
+            k += 1;
+        }
+        let base = match self.read_universe(u) {
+            Zero => return k.to_string(),
+            Param{name, ..} => self.fmt_param(name),
+            Max{v1, v2, ..} => format!("max {} {}", self.fmt_argomento(v1), self.fmt_argomento(v2)),
+            IMax{v1, v2, ..} => format!("imax {} {}", self.fmt_argomento(v1), self.fmt_argomento(v2)),
+            Succ{..} => unreachable!(),
         };
-        out
+        match k {
+            0 => base,
+            _ if matches!(self.read_universe(u), Param{..}) => format!("{}+{}", base, k),
+            _ => format!("({})+{}", base, k),
+        }
+    }
+
+    pub fn fmt_argomento(&self, universe: UniversePtr<'t>) -> String {
+        let s = self.fmt_universe(universe);
+        match self.read_universe(universe) {
+            Zero | Param{..} => s,
+            Succ{..} if !s.contains(['+', ' ']) => s,
+            _ => format!("({})", s),
+        }
+    }
+
+    fn fmt_param(&self, name: NamePtr<'t>) -> String {
+        match self.read_name(name) {
+            Name::Str{value, ..} => value,
+            Name::Num{value, ..} => value.to_string(),
+            Name::Anon => "_".to_string(),
+        }
+    }
+
+    pub fn fmt_assegnamento(&self, ass: &Assegnamento<'t>) -> String {
+        ass.iter().map(|(p, v)| format!("{} := {}", self.fmt_param(*p), v)).collect::<Vec<_>>().join(", ")
+    }
+
+    pub fn fmt_fallimento(&self, f: &FallimentoUniverso<'t>) -> String {
+        format!("{} ≤ {} falso per {} ({} contro {})", self.fmt_universe(f.l), self.fmt_universe(f.r),
+            self.fmt_assegnamento(&f.ass), self.eval_universe(f.l, &f.ass), self.eval_universe(f.r, &f.ass))
     }
 
     pub fn subst_universes(&mut self, uparams: UparamsPtr<'t>, ups_dec: UparamsPtr<'t>, ups_const: UparamsPtr<'t>) -> UparamsPtr<'t> {
@@ -210,5 +399,254 @@ impl<'t> ExportFile<'t> {
             }
         }
     }
+    pub fn eval_universe(&self, universe: UniversePtr<'t>, ass: &Assegnamento<'t>) -> u64 {
+        match self.read_universe(universe) {
+            Zero => 0,
+            Succ{pred, ..} => self.eval_universe(pred, ass) + 1,
+            Max{v1, v2, ..} => self.eval_universe(v1, ass).max(self.eval_universe(v2, ass)),
+            IMax{v1, v2, ..} => match self.eval_universe(v2, ass) {
+                0 => 0,
+                r => self.eval_universe(v1, ass).max(r),
+            }
+            Param{name, ..} => *ass.get(&name).unwrap_or_else(|| panic!("eval_universe: parametro {:?} non assegnato", name.idx)),
+        }
+    }
 
+    pub fn params_universe(&self, universe: UniversePtr<'t>, out: &mut Vec<NamePtr<'t>>) {
+        match self.read_universe(universe) {
+            Zero => {}
+            Succ{pred, ..} => self.params_universe(pred, out),
+            Max{v1, v2, ..} | IMax{v1, v2, ..} => {
+                self.params_universe(v1, out);
+                self.params_universe(v2, out);
+            }
+            Param{name, ..} => if !out.contains(&name) { out.push(name) },
+        }
+    }
+
+    fn conta_succ(&self, universe: UniversePtr<'t>) -> u64 {
+        match self.read_universe(universe) {
+            Zero | Param{..} => 0,
+            Succ{pred, ..} => self.conta_succ(pred) + 1,
+            Max{v1, v2, ..} | IMax{v1, v2, ..} => self.conta_succ(v1) + self.conta_succ(v2),
+        }
+    }
+
+    // se l <= r è falso esiste un controesempio con valori non oltre il numero di succ in l e r, più 2
+    pub fn limite_enumerazione(&self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> u64 {
+        self.conta_succ(l) + self.conta_succ(r) + 2
+    }
+
+    // cerca, provandoli tutti, un assegnamento con valori in 0..=limite per cui l > r
+    pub fn controesempio_leq(&self, l: UniversePtr<'t>, r: UniversePtr<'t>, limite: u64) -> Option<Assegnamento<'t>> {
+        let mut params = Vec::new();
+        self.params_universe(l, &mut params);
+        self.params_universe(r, &mut params);
+        let mut ass: Assegnamento<'t> = params.into_iter().map(|p| (p, 0)).collect();
+        loop {
+            if self.eval_universe(l, &ass) > self.eval_universe(r, &ass) {
+                return Some(ass)
+            }
+            // assegnamento successivo, come un contatore in base limite + 1
+            let mut i = 0;
+            while i < ass.len() && ass[i] == limite {
+                ass[i] = 0;
+                i += 1;
+            }
+            if i == ass.len() {
+                return None
+            }
+            ass[i] += 1;
+        }
+    }
+
+    pub fn leq_enumerato(&self, l: UniversePtr<'t>, r: UniversePtr<'t>) -> bool {
+        let limite = self.limite_enumerazione(l, r);
+        self.controesempio_leq(l, r, limite).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Environment;
+
+    fn param<'t>(tc: &mut TypeChecker<'_, 't>, nome: &str) -> UniversePtr<'t> {
+        let name = tc.mk_str(tc.anonymous(), nome.to_string());
+        tc.param(name)
+    }
+
+    fn controlla_controesempio<'t>(tc: &mut TypeChecker<'_, 't>, l: UniversePtr<'t>, r: UniversePtr<'t>) {
+        let ass = tc.controesempio(l, r).expect("atteso un controesempio");
+        assert!(tc.eval_universe(l, &ass) > tc.eval_universe(r, &ass));
+    }
+
+    #[test]
+    fn max_commutativo() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let (u, v) = (param(&mut tc, "u"), param(&mut tc, "v"));
+        let (uv, vu) = (tc.max(u, v), tc.max(v, u));
+        assert!(tc.leq(uv, vu));
+        assert!(tc.leq(vu, uv));
+        assert!(tc.eq_universe(uv, vu));
+        assert!(tc.leq(u, uv));
+        assert!(!tc.leq(uv, u));
+        controlla_controesempio(&mut tc, uv, u);
+    }
+
+    #[test]
+    fn max_con_succ() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let (u, v) = (param(&mut tc, "u"), param(&mut tc, "v"));
+        let (su, sv) = (tc.succ(u), tc.succ(v));
+        let uv = tc.max(u, v);
+        let s_uv = tc.succ(uv);
+        let su_sv = tc.max(su, sv);
+        assert!(tc.eq_universe(s_uv, su_sv));
+        // 1 ≤ u+1 ma non 2 ≤ max (u+1) v
+        let uno = tc.succ(tc.zero());
+        let due = tc.succ(uno);
+        assert!(tc.leq(uno, su));
+        let su_v = tc.max(su, v);
+        assert!(!tc.leq(due, su_v));
+        controlla_controesempio(&mut tc, due, su_v);
+    }
+
+    #[test]
+    fn imax_con_parametro() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let (u, v) = (param(&mut tc, "u"), param(&mut tc, "v"));
+        let imax_uv = tc.imax(u, v);
+        let max_uv = tc.max(u, v);
+        // con v = 0 imax u v è 0, altrimenti è max u v
+        assert!(tc.leq(imax_uv, max_uv));
+        assert!(!tc.leq(max_uv, imax_uv));
+        controlla_controesempio(&mut tc, max_uv, imax_uv);
+        assert!(tc.leq(v, imax_uv));
+        assert!(!tc.leq(imax_uv, v));
+        controlla_controesempio(&mut tc, imax_uv, v);
+        // imax u u = u
+        let imax_uu = tc.imax(u, u);
+        assert!(tc.eq_universe(imax_uu, u));
+        // imax 1 u = u, serve separare u = 0 da u > 0
+        let uno = tc.succ(tc.zero());
+        let imax_1u = tc.imax(uno, u);
+        assert!(tc.eq_universe(imax_1u, u));
+        // imax u (v+1) = max u (v+1)
+        let sv = tc.succ(v);
+        let (a, b) = (tc.imax(u, sv), tc.max(u, sv));
+        assert!(tc.eq_universe(a, b));
+    }
+
+    #[test]
+    fn imax_annidati() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let (u, v, w) = (param(&mut tc, "u"), param(&mut tc, "v"), param(&mut tc, "w"));
+        // imax u (imax v w) = max (imax u w) (imax v w)
+        let vw = tc.imax(v, w);
+        let l = tc.imax(u, vw);
+        let uw = tc.imax(u, w);
+        let r = tc.max(uw, vw);
+        assert!(tc.eq_universe(l, r));
+        // (imax u v)+1 ≤ max (u+1) (v+1)
+        let uv = tc.imax(u, v);
+        let s_uv = tc.succ(uv);
+        let (su, sv) = (tc.succ(u), tc.succ(v));
+        let su_sv = tc.max(su, sv);
+        assert!(tc.leq(s_uv, su_sv));
+        assert!(!tc.leq(su_sv, s_uv));
+        controlla_controesempio(&mut tc, su_sv, s_uv);
+    }
+
+    // xorshift, per avere casi diversi ma ripetibili senza dipendenze
+    struct Casuale(u64);
+
+    impl Casuale {
+        fn sotto(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn livello<'t>(tc: &mut TypeChecker<'_, 't>, rng: &mut Casuale, params: &[UniversePtr<'t>], profondita: u32) -> UniversePtr<'t> {
+        let scelta = if profondita == 0 { rng.sotto(2) } else { rng.sotto(5) };
+        match scelta {
+            0 => tc.zero(),
+            1 => params[rng.sotto(params.len() as u64) as usize],
+            2 => {
+                let pred = livello(tc, rng, params, profondita - 1);
+                tc.succ(pred)
+            }
+            3 => {
+                let (a, b) = (livello(tc, rng, params, profondita - 1), livello(tc, rng, params, profondita - 1));
+                tc.max(a, b)
+            }
+            _ => {
+                let (a, b) = (livello(tc, rng, params, profondita - 1), livello(tc, rng, params, profondita - 1));
+                tc.imax(a, b)
+            }
+        }
+    }
+
+    // tutti gli assegnamenti di names con valori in 0..=limite
+    fn assegnamenti<'t>(names: &[NamePtr<'t>], limite: u64) -> Vec<Assegnamento<'t>> {
+        let mut out = vec![new_fx_index_map()];
+        for &n in names {
+            out = out.into_iter()
+                .flat_map(|ass: Assegnamento<'t>| (0..=limite).map(move |v| {
+                    let mut ass = ass.clone();
+                    ass.insert(n, v);
+                    ass
+                }))
+                .collect();
+        }
+        out
+    }
+
+    #[test]
+    fn leq_come_enumerazione() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let params = ["u", "v", "w"].map(|p| param(&mut tc, p));
+        let mut rng = Casuale(0x2545f4914f6cdd1d);
+        for _ in 0..2000 {
+            let l = livello(&mut tc, &mut rng, &params, 3);
+            let r = livello(&mut tc, &mut rng, &params, 3);
+            assert_eq!(tc.leq(l, r), tc.leq_enumerato(l, r), "{} ≤ {}", tc.fmt_universe(l), tc.fmt_universe(r));
+        }
+    }
+
+    #[test]
+    fn semplifica_e_subst_conservano_il_valore() {
+        let env = Environment::new();
+        let mut tc = TypeChecker::new(&env);
+        let params = ["u", "v", "w"].map(|p| param(&mut tc, p));
+        let names = params.map(|p| match tc.read_universe(p) {
+            Param { name, .. } => name,
+            _ => unreachable!(),
+        });
+        let tutti = assegnamenti(&names, 3);
+        let mut rng = Casuale(0x9e3779b97f4a7c15);
+        for _ in 0..500 {
+            let l = livello(&mut tc, &mut rng, &params, 3);
+            let s = tc.semplifica(l);
+            // u := t, con t che può contenere u
+            let t = livello(&mut tc, &mut rng, &params, 2);
+            let (dec, val) = (tc.alloc_uparams(vec![params[0]]), tc.alloc_uparams(vec![t]));
+            let sub = tc.subst_universe(l, dec, val);
+            for ass in &tutti {
+                let e = tc.eval_universe(l, ass);
+                assert_eq!(e, tc.eval_universe(s, ass), "semplifica {}", tc.fmt_universe(l));
+                let mut ass_t = ass.clone();
+                ass_t.insert(names[0], tc.eval_universe(t, ass));
+                assert_eq!(tc.eval_universe(sub, ass), tc.eval_universe(l, &ass_t), "subst in {}", tc.fmt_universe(l));
+            }
+        }
+    }
 }
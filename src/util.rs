@@ -6,6 +6,10 @@ use crate::declar::{RecRule, Declar, RecRulePtr};
 use crate::expr::{ExprPtr, Expr};
 use crate::universe::{UniversePtr, UparamsPtr, Universe, Universe::Zero};
 use crate::name::{NamePtr, Name, Name::Anon};
+use crate::stats::Statistiche;
+use crate::profile::Profilo;
+use crate::tc::Limiti;
+use std::time::Instant;
 
 pub type FxIndexSet<A> = IndexSet<A, BuildHasherDefault<FxHasher>>;
 pub type FxIndexMap<K, V> = IndexMap<K, V, BuildHasherDefault<FxHasher>>;
@@ -63,26 +67,122 @@ macro_rules! reader_map {
     };
 }
 
-macro_rules! alloc {
+// gli indici fino a env.$field.len() sono dell'environment, i successivi del type checker
+macro_rules! reader_ext {
+    ($fn_name:ident, $get_name:ident, $len_name:ident, $field:ident, $ptr:ty, $ret:ty) => {
+        pub fn $get_name(&self, p: $ptr) -> &$ret {
+            let base = self.env.$field.len();
+            if p.idx < base {
+                self.env.$field.get_index(p.idx).unwrap()
+            } else {
+                self.$field.get_index(p.idx - base).unwrap()
+            }
+        }
+
+        pub fn $fn_name(&self, p: $ptr) -> $ret {
+            self.$get_name(p).clone()
+        }
+
+        pub fn $len_name(&self) -> usize {
+            self.env.$field.len() + self.$field.len()
+        }
+    };
+}
+
+macro_rules! alloc_ext {
     ($fn_name:ident, $field:ident, $ptr:ty, $ret:ty) => {
         pub fn $fn_name(&mut self, n: $ptr) -> $ret {
-            Ptr::from(self.$field.insert_full(n).0)
+            match self.env.$field.get_index_of(&n) {
+                Some(idx) => Ptr::from(idx),
+                None => Ptr::from(self.env.$field.len() + self.$field.insert_full(n).0),
+            }
         }
     };
 }
 
-pub struct ExportFile<'p> {
+// union-find sulle espressioni che il def_eq ha già dimostrato uguali
+pub struct Equivalenze<'p> {
+    nodi: FxIndexSet<ExprPtr<'p>>,
+    padri: Vec<usize>,
+    ranghi: Vec<u32>,
+}
+
+impl<'p> Default for Equivalenze<'p> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'p> Equivalenze<'p> {
+    pub fn new() -> Self {
+        Self { nodi: new_fx_index_set(), padri: Vec::new(), ranghi: Vec::new() }
+    }
+
+    fn nodo(&mut self, e: ExprPtr<'p>) -> usize {
+        let (i, nuovo) = self.nodi.insert_full(e);
+        if nuovo {
+            self.padri.push(i);
+            self.ranghi.push(0);
+        }
+        i
+    }
+
+    fn radice(&mut self, mut i: usize) -> usize {
+        let mut r = i;
+        while self.padri[r] != r {
+            r = self.padri[r];
+        }
+        // compressione dei cammini
+        while self.padri[i] != r {
+            let p = self.padri[i];
+            self.padri[i] = r;
+            i = p;
+        }
+        r
+    }
+
+    pub fn equivalenti(&mut self, a: ExprPtr<'p>, b: ExprPtr<'p>) -> bool {
+        match (self.nodi.get_index_of(&a), self.nodi.get_index_of(&b)) {
+            (Some(i), Some(j)) => self.radice(i) == self.radice(j),
+            _ => false,
+        }
+    }
+
+    pub fn unisci(&mut self, a: ExprPtr<'p>, b: ExprPtr<'p>) {
+        let (i, j) = (self.nodo(a), self.nodo(b));
+        let (ri, rj) = (self.radice(i), self.radice(j));
+        if ri == rj {
+            return
+        }
+        match self.ranghi[ri].cmp(&self.ranghi[rj]) {
+            std::cmp::Ordering::Less => self.padri[ri] = rj,
+            std::cmp::Ordering::Greater => self.padri[rj] = ri,
+            std::cmp::Ordering::Equal => {
+                self.padri[rj] = ri;
+                self.ranghi[ri] += 1;
+            }
+        }
+    }
+}
+
+// dichiarazioni e termini dell'export file, non cambiano durante il controllo
+pub struct Environment<'p> {
     pub names: FxIndexSet<Name<'p>>,
     pub universes: FxIndexSet<Universe<'p>>,
     pub exprs: FxIndexSet<Expr<'p>>,
     pub rec_rules: FxIndexSet<RecRule<'p>>,
     pub uparams: FxIndexSet<Vec<UniversePtr<'p>>>,
     pub declars: FxIndexMap<NamePtr<'p>, Declar<'p>>,
-    pub infers: FxIndexMap<ExprPtr<'p>, ExprPtr<'p>>,
-    pub whnfs: FxIndexMap<ExprPtr<'p>, ExprPtr<'p>>,
+    pub declar_lines: FxIndexMap<NamePtr<'p>, u32>,
+}
+
+impl<'t> Default for Environment<'t> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<'t> ExportFile<'t> {
+impl<'t> Environment<'t> {
     pub fn new() -> Self {
         let mut out = Self {
             names: new_fx_index_set(),
@@ -91,8 +191,7 @@ impl<'t> ExportFile<'t> {
             rec_rules: new_fx_index_set(),
             uparams: new_fx_index_set(),
             declars: new_fx_index_map(),
-            infers: new_fx_index_map(),
-            whnfs: new_fx_index_map(),
+            declar_lines: new_fx_index_map(),
         };
         out.names.insert(Anon);
         out.universes.insert(Zero);
@@ -103,18 +202,106 @@ impl<'t> ExportFile<'t> {
     reader!(read_expr, exprs, ExprPtr<'t>, Expr<'t>);
     reader!(read_universe, universes, UniversePtr<'t>, Universe<'t>);
     reader!(read_uparams, uparams, UparamsPtr<'t>, Vec<UniversePtr<'t>>);
-    
+    reader_map!(read_declar, declars, NamePtr<'t>, Declar<'t>);
+}
+
+// stato del controllo: i termini creati durante il controllo (con indici che seguono
+// quelli dell'environment), le cache e il contatore delle variabili libere
+pub struct TypeChecker<'e, 'p> {
+    pub env: &'e Environment<'p>,
+    pub names: FxIndexSet<Name<'p>>,
+    pub universes: FxIndexSet<Universe<'p>>,
+    pub exprs: FxIndexSet<Expr<'p>>,
+    pub rec_rules: FxIndexSet<RecRule<'p>>,
+    pub uparams: FxIndexSet<Vec<UniversePtr<'p>>>,
+    pub infers: FxIndexMap<ExprPtr<'p>, ExprPtr<'p>>,
+    pub whnfs: FxIndexMap<ExprPtr<'p>, ExprPtr<'p>>,
+    pub equivalenze: Equivalenze<'p>,
+    // coppie per cui il def_eq è fallito, con l'eventuale vincolo di universo violato
+    pub def_eq_falliti: FxIndexMap<(ExprPtr<'p>, ExprPtr<'p>), Option<(UniversePtr<'p>, UniversePtr<'p>)>>,
+    // il vincolo l = r che ha fatto fallire l'ultimo def_eq, se c'è
+    pub fallimento_universo: Option<(UniversePtr<'p>, UniversePtr<'p>)>,
+    pub next_free_var: u32,
+    pub statistiche: Statistiche,
+    // None se la traccia è disattivata
+    pub profilo: Option<Profilo>,
+    pub limiti: Limiti,
+    // passi consumati e scadenza della dichiarazione in controllo
+    pub passi: u64,
+    pub scadenza: Option<Instant>,
+    pub declar_corrente: Option<NamePtr<'p>>,
+    // chiamate annidate di infer, whnf e def_eq
+    pub ricorsione: u32,
+}
+
+impl<'e, 't> TypeChecker<'e, 't> {
+    pub fn new(env: &'e Environment<'t>) -> Self {
+        Self {
+            env,
+            names: new_fx_index_set(),
+            universes: new_fx_index_set(),
+            exprs: new_fx_index_set(),
+            rec_rules: new_fx_index_set(),
+            uparams: new_fx_index_set(),
+            infers: new_fx_index_map(),
+            whnfs: new_fx_index_map(),
+            equivalenze: Equivalenze::new(),
+            def_eq_falliti: new_fx_index_map(),
+            fallimento_universo: None,
+            next_free_var: 0,
+            statistiche: Statistiche::default(),
+            profilo: None,
+            limiti: Limiti::default(),
+            passi: 0,
+            scadenza: None,
+            declar_corrente: None,
+            ricorsione: 0,
+        }
+    }
+
+    reader_ext!(read_name, get_name, num_names, names, NamePtr<'t>, Name<'t>);
+    reader_ext!(read_expr, get_expr, num_exprs, exprs, ExprPtr<'t>, Expr<'t>);
+    reader_ext!(read_universe, get_universe, num_universes, universes, UniversePtr<'t>, Universe<'t>);
+    reader_ext!(read_uparams, get_uparams, num_uparams, uparams, UparamsPtr<'t>, Vec<UniversePtr<'t>>);
+    reader_ext!(read_rec_rule, get_rec_rule, num_rec_rules, rec_rules, RecRulePtr<'t>, RecRule<'t>);
+
     pub fn read_expr_pair(&self, a: ExprPtr<'t>, b: ExprPtr<'t>) -> (Expr<'t>, Expr<'t>) {
         (self.read_expr(a), self.read_expr(b))
     }
 
-    reader_map!(read_declar, declars, NamePtr<'t>, Declar<'t>);
+    pub fn read_declar(&self, n: NamePtr<'t>) -> Declar<'t> {
+        self.env.read_declar(n)
+    }
+
     reader_map!(read_infer, infers, ExprPtr<'t>, ExprPtr<'t>);
     reader_map!(read_whnf, whnfs, ExprPtr<'t>, ExprPtr<'t>);
-    
-    alloc!(alloc_name, names, Name<'t>, NamePtr<'t>);
-    alloc!(alloc_expr, exprs, Expr<'t>, ExprPtr<'t>);
-    alloc!(alloc_universe, universes, Universe<'t>, UniversePtr<'t>);
-    alloc!(alloc_uparams, uparams, Vec<UniversePtr<'t>>, UparamsPtr<'t>);
-    alloc!(alloc_rec_rule, rec_rules, RecRule<'t>, RecRulePtr<'t>);
+
+    alloc_ext!(alloc_name, names, Name<'t>, NamePtr<'t>);
+    alloc_ext!(alloc_expr, exprs, Expr<'t>, ExprPtr<'t>);
+    alloc_ext!(alloc_universe, universes, Universe<'t>, UniversePtr<'t>);
+    alloc_ext!(alloc_uparams, uparams, Vec<UniversePtr<'t>>, UparamsPtr<'t>);
+    alloc_ext!(alloc_rec_rule, rec_rules, RecRule<'t>, RecRulePtr<'t>);
+
+    // i termini creati finiscono in un nuovo environment, dopo quelli di partenza
+    pub fn into_environment(
+        self,
+        declars: FxIndexMap<NamePtr<'t>, Declar<'t>>,
+        declar_lines: FxIndexMap<NamePtr<'t>, u32>,
+    ) -> Environment<'t> {
+        let mut out = Environment {
+            names: self.env.names.clone(),
+            universes: self.env.universes.clone(),
+            exprs: self.env.exprs.clone(),
+            rec_rules: self.env.rec_rules.clone(),
+            uparams: self.env.uparams.clone(),
+            declars,
+            declar_lines,
+        };
+        out.names.extend(self.names);
+        out.universes.extend(self.universes);
+        out.exprs.extend(self.exprs);
+        out.rec_rules.extend(self.rec_rules);
+        out.uparams.extend(self.uparams);
+        out
+    }
 }